@@ -0,0 +1,63 @@
+use std::rc::Rc;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use trenchy_quadtree::quadtree::{QuadTree, TreeSurface};
+use trenchy_quadtree::scene::generate_scene;
+use trenchy_quadtree::Rectangle;
+
+const SEED: u64 = 42;
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+
+fn surface() -> TreeSurface {
+    TreeSurface::from_size(0, 0, 1000, 1000)
+}
+
+fn build(count: usize) -> QuadTree {
+    let mut tree = QuadTree::new(0, 0, 1000, 1000);
+    for object in generate_scene(count, SEED, &surface()) {
+        tree.insert_object(object).unwrap();
+    }
+    tree
+}
+
+fn insert_object(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert_object");
+    for count in SIZES {
+        let scene = generate_scene(count, SEED, &surface());
+        group.bench_with_input(BenchmarkId::from_parameter(count), &scene, |b, scene| {
+            b.iter(|| {
+                let mut tree = QuadTree::new(0, 0, 1000, 1000);
+                for object in scene.iter() {
+                    tree.insert_object(Rc::clone(object)).unwrap();
+                }
+                tree
+            })
+        });
+    }
+    group.finish();
+}
+
+fn query_objects_in(c: &mut Criterion) {
+    let mut group = c.benchmark_group("query_objects_in");
+    let query = Rectangle::new(0, 400, 400, 200, 200);
+    for count in SIZES {
+        let tree = build(count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &tree, |b, tree| {
+            b.iter(|| tree.query_objects_in(black_box(&query)))
+        });
+    }
+    group.finish();
+}
+
+fn nearest(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nearest");
+    for count in SIZES {
+        let tree = build(count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &tree, |b, tree| {
+            b.iter(|| tree.nearest(black_box(517), black_box(283)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert_object, query_objects_in, nearest);
+criterion_main!(benches);
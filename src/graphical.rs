@@ -2,9 +2,11 @@ use std::cell::RefCell;
 use std::rc::Rc;
 use macroquad::text::draw_text;
 use macroquad::time::get_fps;
-use macroquad::window::{clear_background};
+use macroquad::window::{clear_background, screen_height, screen_width};
 use macroquad::color::{BLACK, WHITE, YELLOW};
 use std::time::Instant;
+use trenchy_quadtree::quad_objects::by_z_index;
+use trenchy_quadtree::Rectangle;
 use crate::{InputStore, QuadObject, QuadTree};
 
 pub struct TimingStruct {
@@ -44,14 +46,19 @@ pub fn draw_performance(time_struct: &TimingStruct, quadtree: &QuadTree) {
     draw_text(info_str.as_str(), draw_x, 340.0, 15.0, WHITE);
 
     info_str.clear();
-    info_str.push_str("  Objectquery: ");
-    info_str.push_str(&(time_struct.after_query_by_object - time_struct.after_quadtree).as_micros().to_string());
+    info_str.push_str("  Object update: ");
+    info_str.push_str(&(time_struct.after_object_update - time_struct.after_quadtree).as_micros().to_string());
     draw_text(info_str.as_str(), draw_x, 360.0, 15.0, WHITE);
 
+    info_str.clear();
+    info_str.push_str("  Objectquery: ");
+    info_str.push_str(&(time_struct.after_query_by_object - time_struct.after_object_update).as_micros().to_string());
+    draw_text(info_str.as_str(), draw_x, 380.0, 15.0, WHITE);
+
     info_str.clear();
     info_str.push_str("Draw: ");
     info_str.push_str(&(time_struct.after_draw - time_struct.after_update).as_micros().to_string());
-    draw_text(info_str.as_str(), draw_x, 380.0, 15.0, WHITE);
+    draw_text(info_str.as_str(), draw_x, 400.0, 15.0, WHITE);
 }
 
 // --------------------
@@ -60,16 +67,17 @@ pub fn draw_performance(time_struct: &TimingStruct, quadtree: &QuadTree) {
 pub fn draw(input_store: &mut InputStore, object_array: &mut Vec<Rc<RefCell<dyn QuadObject>>>, quadtree: &mut QuadTree) {
     clear_background(BLACK);
 
-    // Normal draws
-    quadtree.draw();
-    for object in object_array.iter() {
+    // Normal draws, only what is on screen
+    quadtree.draw(&input_store.draw_style);
+    let viewport = Rectangle::new(object_array.len() as u32, 0, 0, screen_width() as i32, screen_height() as i32);
+    for object in by_z_index(&quadtree.visible_objects(&viewport)).iter() {
         object.borrow().draw();
     }
 
     // Highlight by red
     let red = object_array.first().unwrap();
     let query = quadtree.query_neighbours_and_condition(red, Some(10));
-    for object in query.iter() {
+    for object in by_z_index(&query).iter() {
         object.borrow().highlight();
     }
 
@@ -88,7 +96,7 @@ pub fn draw(input_store: &mut InputStore, object_array: &mut Vec<Rc<RefCell<dyn
             draw_text(info_str.as_str(), 25.0, 20.0, 15.0, YELLOW);
 
             // Objects
-            for object in objects {
+            for object in by_z_index(objects).iter() {
                 object.borrow().highlight();
             }
         }
@@ -99,4 +107,9 @@ pub fn draw(input_store: &mut InputStore, object_array: &mut Vec<Rc<RefCell<dyn
     let len = &object_array.len().to_string();
     info_str.push_str(len);
     draw_text(info_str.as_str(), 120.0, 20.0, 15.0, WHITE);
-}
\ No newline at end of file
+
+    // Seed, to reproduce this run with QUADTREE_SEED
+    let mut info_str = String::from("Seed: ");
+    info_str.push_str(&input_store.seed.to_string());
+    draw_text(info_str.as_str(), 240.0, 20.0, 15.0, WHITE);
+}
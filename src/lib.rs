@@ -0,0 +1,8 @@
+pub mod quadtree;
+pub mod quadtree_sync;
+pub mod quad_objects;
+pub mod scene;
+#[cfg(test)]
+mod test_object;
+
+pub use crate::quad_objects::{QuadObject, Rectangle};
@@ -6,12 +6,10 @@ use macroquad::input::is_key_down;
 use macroquad::prelude::{Conf, KeyCode};
 use macroquad::window::{next_frame};
 use crate::graphical::{draw, draw_performance, TimingStruct};
-use crate::main_loop::{handle_input, InputStore, setup_shapes, update};
-use crate::quad_objects::{QuadObject, Rectangle};
-use crate::quadtree::QuadTree;
+use crate::main_loop::{handle_input, InputStore, seed_from_env, setup_shapes, update};
+use trenchy_quadtree::quad_objects::QuadObject;
+use trenchy_quadtree::quadtree::QuadTree;
 
-mod quadtree;
-mod quad_objects;
 mod main_loop;
 mod graphical;
 
@@ -29,12 +27,12 @@ fn window_conf() -> Conf {
 // --------------------
 #[macroquad::main(window_conf)]
 async fn main() {
-    // Input to update setup
-    let input_control = &mut InputStore{ is_selection:false, selected: None, selected_objects: None, do_quadtree:true };
-
     // Simulation setup
     let mut run_simulation = true;
     let object_array: &mut Vec<Rc<RefCell<dyn QuadObject>>> = &mut setup_shapes();
+
+    // Input to update setup, spawned ids continue after the setup
+    let input_control = &mut InputStore::new(seed_from_env(), object_array.len() as u32);
     let mut quadtree = QuadTree::new(25, 25, 500, 500);
 
     // Loop
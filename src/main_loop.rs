@@ -3,13 +3,25 @@ use std::rc::Rc;
 use std::time::Instant;
 use macroquad::input::{is_key_pressed, is_mouse_button_down, is_mouse_button_pressed, is_mouse_button_released, mouse_position, MouseButton};
 use macroquad::prelude::{KeyCode};
-use macroquad::rand::ChooseRandom;
 
-use crate::quad_objects::{QuadObject, Rectangle, Circle, Boid};
-use crate::quadtree::QuadTree;
-use rand::{Rng, thread_rng};
+use trenchy_quadtree::quad_objects::{QuadObject, Rectangle, Circle, Boid, BoidPool};
+use trenchy_quadtree::quadtree::{Adjacency, DrawStyle, QuadTree};
+use rand::{Rng, SeedableRng, thread_rng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
 use crate::TimingStruct;
 
+const SEED_ENV_VAR: &str = "QUADTREE_SEED";
+const POOL_SIZE: usize = 1000;
+
+// Seed from QUADTREE_SEED when set, otherwise a random one
+pub fn seed_from_env() -> u64 {
+    match std::env::var(SEED_ENV_VAR).ok().and_then(|seed| seed.parse().ok()) {
+        Some(seed) => seed,
+        None => thread_rng().gen(),
+    }
+}
+
 pub fn setup_shapes() -> Vec<Rc<RefCell<dyn QuadObject>>> {
     let mut input_vec: Vec<Rc<RefCell<dyn QuadObject>>> = vec![];
 
@@ -50,6 +62,20 @@ pub struct InputStore {
     pub selected_objects: Option<Vec<Rc<RefCell<dyn QuadObject>>>>,
 
     pub do_quadtree: bool,
+    pub paused: bool, // Objects stand still, the tree is still rebuilt and queried
+    pub draw_style: DrawStyle,
+
+    pub seed: u64,
+    pub rng: StdRng, // Seeded from `seed`, used for every spawn
+    pub pool: BoidPool, // Spawned boids come from here and return once they expire or are removed
+    pub next_id: u32, // Id of the next spawn, only ever grows so an expired object's id is never handed out again
+}
+
+impl InputStore {
+    // Spawns are numbered from first_id up, past the ids of the objects set up beforehand
+    pub fn new(seed: u64, first_id: u32) -> InputStore {
+        InputStore { is_selection:false, selected: None, selected_objects: None, do_quadtree:true, paused:false, draw_style: DrawStyle::default(), seed, rng: StdRng::seed_from_u64(seed), pool: BoidPool::new(POOL_SIZE), next_id: first_id }
+    }
 }
 
 // --------------------
@@ -60,23 +86,26 @@ pub fn handle_input(input_store: &mut InputStore, object_array: &mut Vec<Rc<RefC
     if is_key_pressed(KeyCode::Q) {
         input_store.do_quadtree = false;
     }
+    // Pause the simulation
+    if is_key_pressed(KeyCode::Space) {
+        input_store.paused = !input_store.paused;
+    }
+    // Toggle text overlays
+    if is_key_pressed(KeyCode::C) {
+        input_store.draw_style.node_counts = !input_store.draw_style.node_counts;
+    }
+    if is_key_pressed(KeyCode::I) {
+        input_store.draw_style.info = !input_store.draw_style.info;
+    }
 
     // Add object
     if is_mouse_button_pressed(MouseButton::Right) {
-        let mut rng = thread_rng();
-
         let (mx, my) = mouse_position();
-        object_array.push(Rc::new(RefCell::new(Boid::new(object_array.len() as u32,mx as i32, my as i32, rng.gen_range(0.0..6.0) as f32))));
+        spawn_boid(input_store, object_array, mx as i32, my as i32);
     }
     // Add 100
     if is_key_pressed(KeyCode::Up) {
-        let mut rng = thread_rng();
-        let mut nums: Vec<i32> = (1..100).collect();
-        nums.shuffle();
-        let pos_iter = nums.iter().zip( nums.iter().rev() );
-        for (x, y) in pos_iter {
-            object_array.push(Rc::new(RefCell::new(Boid::new(object_array.len() as u32,*x * 10 + 100, *y * 10 + 100, rng.gen_range(0.0..6.0) as f32))));
-        }
+        spawn_hundred(input_store, object_array);
     }
     if is_key_pressed(KeyCode::Down) {
         for _ in 1..100 {
@@ -107,22 +136,63 @@ pub fn handle_input(input_store: &mut InputStore, object_array: &mut Vec<Rc<RefC
     }
 }
 
+// --------------------
+// Spawning
+// --------------------
+// A boid at the position with a heading from the seeded rng
+pub fn spawn_boid(input_store: &mut InputStore, object_array: &mut Vec<Rc<RefCell<dyn QuadObject>>>, x: i32, y: i32) {
+    let facing = input_store.rng.gen_range(0.0..6.0) as f32;
+    object_array.push(input_store.pool.acquire(input_store.next_id, x, y, facing));
+    input_store.next_id += 1;
+}
+
+// 99 boids on a diagonal, shuffled by the seeded rng
+pub fn spawn_hundred(input_store: &mut InputStore, object_array: &mut Vec<Rc<RefCell<dyn QuadObject>>>) {
+    let mut nums: Vec<i32> = (1..100).collect();
+    nums.shuffle(&mut input_store.rng);
+    let pos_iter = nums.iter().zip( nums.iter().rev() );
+    for (x, y) in pos_iter {
+        spawn_boid(input_store, object_array, *x * 10 + 100, *y * 10 + 100);
+    }
+}
+
 // --------------------
 // Update
 // --------------------
+// Ticks every lifetime and drops the objects that ran out
+pub fn expire_dead(objects: &mut Vec<Rc<RefCell<dyn QuadObject>>>) {
+    objects.retain(|object| object.as_ref().borrow_mut().tick_lifetime());
+}
+
+// Moves every object one step, nothing moves while paused
+pub fn step_simulation(objects: &[Rc<RefCell<dyn QuadObject>>], paused: bool) {
+    if paused { return; }
+    for object in objects.iter() {
+        object.as_ref().borrow_mut().update();
+    }
+}
+
 pub fn update(timing_struct: &mut TimingStruct, input_store: &mut InputStore, object_array: &mut Vec<Rc<RefCell<dyn QuadObject>>>, quadtree: &mut QuadTree) {
+    // Lifetimes stand still with the objects
+    if !input_store.paused { expire_dead(object_array); }
+
     // Setup quadtree
     quadtree.clear();
+    input_store.pool.reclaim();
     for object in object_array.iter() {
-        quadtree.insert_object(Rc::clone(object));
-        object.as_ref().borrow_mut().update();
+        let _ = quadtree.insert_object(Rc::clone(object));
     }
     timing_struct.after_quadtree = Instant::now();
-    // Operation
-    for object in object_array.iter() {
-        let query = quadtree.query_neighbours_and_condition(&object.clone(), Some(10));
-        for query_object in query.iter() {
-            query_object.as_ref().borrow_mut().update_movement(object);
+    // Movement
+    step_simulation(object_array, input_store.paused);
+    timing_struct.after_object_update = Instant::now();
+    // Operation, steering is skipped too so headings stay frozen
+    if !input_store.paused {
+        for object in object_array.iter() {
+            let query = quadtree.query_neighbours_with_adjacency(object, Adjacency::EightWay);
+            for query_object in query.iter() {
+                query_object.as_ref().borrow_mut().update_movement(object);
+            }
         }
     }
     timing_struct.after_query_by_object = Instant::now();
@@ -137,3 +207,76 @@ pub fn update(timing_struct: &mut TimingStruct, input_store: &mut InputStore, ob
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use trenchy_quadtree::quad_objects::{Boid, QuadObject, Rectangle};
+    use trenchy_quadtree::quadtree::QuadTree;
+    use super::{expire_dead, setup_shapes, spawn_boid, spawn_hundred, step_simulation, InputStore};
+
+    fn records(objects: &[Rc<RefCell<dyn QuadObject>>]) -> Vec<Vec<u8>> {
+        objects.iter().map(|object| object.as_ref().borrow().to_bytes()).collect()
+    }
+
+    // Setup and both spawn paths, as records holding id, position and heading
+    fn seeded_scene(seed: u64) -> Vec<Vec<u8>> {
+        let mut objects = setup_shapes();
+        let mut input_store = InputStore::new(seed, objects.len() as u32);
+        spawn_hundred(&mut input_store, &mut objects);
+        spawn_boid(&mut input_store, &mut objects, 300, 300);
+        records(&objects)
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_scene() {
+        assert_eq!(seeded_scene(7), seeded_scene(7));
+        assert_ne!(seeded_scene(7), seeded_scene(8));
+    }
+
+    #[test]
+    fn boid_with_ttl_three_is_gone_after_four_ticks() {
+        let mut objects: Vec<Rc<RefCell<dyn QuadObject>>> = vec![Rc::new(RefCell::new(Boid::new(0, 10, 10, 0.0).with_ttl(3))), Rc::new(RefCell::new(Boid::new(1, 20, 20, 0.0)))];
+        expire_dead(&mut objects);
+        expire_dead(&mut objects);
+        assert_eq!(objects.len(), 2);
+        expire_dead(&mut objects);
+        expire_dead(&mut objects);
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].as_ref().borrow().get_id(), 1);
+    }
+
+    #[test]
+    fn spawns_after_a_removal_get_a_fresh_id() {
+        let mut objects = setup_shapes();
+        let mut input_store = InputStore::new(1, objects.len() as u32);
+        spawn_boid(&mut input_store, &mut objects, 100, 100);
+        let first = objects.pop().unwrap().as_ref().borrow().get_id();
+        spawn_boid(&mut input_store, &mut objects, 100, 100);
+
+        let second = objects.last().unwrap().as_ref().borrow().get_id();
+        assert_eq!(first, objects.len() as u32 - 1);
+        assert_eq!(second, first + 1);
+    }
+
+    #[test]
+    fn paused_step_keeps_positions_and_the_tree_queryable() {
+        let objects = setup_shapes();
+        let before = records(&objects);
+        step_simulation(&objects, true);
+        assert_eq!(records(&objects), before);
+
+        let mut quadtree = QuadTree::new(0, 0, 1000, 1000);
+        for object in objects.iter() {
+            let _ = quadtree.insert_object(Rc::clone(object));
+        }
+        let (x, y) = objects[0].as_ref().borrow().center();
+        let found = quadtree.query_objects_in(&Rectangle::new(0, x, y, 0, 0));
+        assert!(found.iter().any(|object| Rc::ptr_eq(object, &objects[0])));
+
+        // Running again moves the boids
+        step_simulation(&objects, false);
+        assert_ne!(records(&objects), before);
+    }
+}
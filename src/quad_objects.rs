@@ -12,26 +12,114 @@ use macroquad::shapes::{draw_line, draw_rectangle_lines, draw_triangle_lines};
 
 use crate::quadtree::TreeSurface;
 
+const BOID_FOV: f32 = 1.5 * PI; // Boids ignore the quarter circle right behind them
+const WALL_MIN: f32 = 25.0;
+const WALL_MAX: f32 = 525.0;
+
 //
 // QuadObject Trait
 //
 pub trait QuadObject: Display {
     fn get_id(&self) -> u32;
+    fn set_id(&mut self, id: u32);
 
     fn draw(&self);
     fn highlight(&self);
+    fn z_index(&self) -> i32 { 0 } // Higher is drawn later, on top
+    fn weight(&self) -> u32 { 1 } // How much of a node's capacity the object takes, heavier objects split nodes sooner
+    fn svg_element(&self) -> String; // Shape as a single SVG element, independent of macroquad
+    fn to_bytes(&self) -> Vec<u8>; // Fixed layout record for replication, read back with from_bytes
     fn center(&self) -> (i32, i32);
-    fn is_overlap(&self, surface: &TreeSurface) -> bool;
+    fn bounds(&self) -> TreeSurface; // Axis aligned bounding box, inclusive
+    fn is_overlap(&self, surface: &TreeSurface) -> bool; // Surfaces are inclusive, touching an edge counts as overlap
 
     fn update(&mut self);
+    fn velocity_vec(&self) -> (f32, f32) { (0.0, 0.0) } // Distance moved per update, for predictive queries
+    fn tick_lifetime(&mut self) -> bool { true } // Counts down a frame of lifetime, false once the object expired
+    fn clamp_to(&mut self, _surface: &TreeSurface) {} // Move the object back inside the surface
+    fn translate(&mut self, dx: i32, dy: i32);
+    fn scale(&mut self, factor: f32); // Resize around the center
     fn update_movement(&mut self, rhs: &Rc<RefCell<dyn QuadObject>>);
+    fn on_collide(&mut self, _other: &dyn QuadObject) {} // Called by QuadTree::resolve_collisions for every object this one overlaps
     fn get_boid(&self) -> Option<&Boid>;
+    fn kind(&self) -> ObjectKind { ObjectKind::Other }
+}
+
+// Concrete type behind a QuadObject, to branch on without downcasting
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObjectKind {
+    Boid,
+    Rectangle,
+    Circle,
+    Other,
+}
+
+// Records start with the id as a little endian u32 and the kind as a u8, followed by the shape parameters as
+// little endian 4 byte values
+fn record(id: u32, kind: ObjectKind, parameters: &[[u8; 4]]) -> Vec<u8> {
+    let mut bytes = id.to_le_bytes().to_vec();
+    bytes.push(kind as u8);
+    parameters.iter().for_each(|parameter| bytes.extend_from_slice(parameter));
+    bytes
+}
+
+// Rebuilds an object from QuadObject::to_bytes, None when the record is too short, of another kind or ObjectKind::Other
+pub fn from_bytes(kind: ObjectKind, bytes: &[u8]) -> Option<Rc<RefCell<dyn QuadObject>>> {
+    let id = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?);
+    if *bytes.get(4)? != kind as u8 { return None; }
+    let parameter = |index: usize| -> Option<[u8; 4]> { bytes.get(5 + 4 * index..9 + 4 * index)?.try_into().ok() };
+    let int = |index: usize| parameter(index).map(i32::from_le_bytes);
+    let float = |index: usize| parameter(index).map(f32::from_le_bytes);
+
+    let object: Rc<RefCell<dyn QuadObject>> = match kind {
+        ObjectKind::Boid => {
+            let mut boid = Boid::new(id, 0, 0, float(2)?);
+            (boid.x, boid.y) = (float(0)?, float(1)?);
+            Rc::new(RefCell::new(boid))
+        },
+        ObjectKind::Rectangle => {
+            let (x0, y0, x1, y1) = (int(0)?, int(1)?, int(2)?, int(3)?);
+            Rc::new(RefCell::new(Rectangle::new(id, x0, y0, x1 - x0, y1 - y0)))
+        },
+        ObjectKind::Circle => Rc::new(RefCell::new(Circle::new(id, int(0)?, int(1)?, int(2)?))),
+        ObjectKind::Other => return None,
+    };
+    Some(object)
+}
+
+// Two circles overlap when their centers are at most the sum of the radii apart, their bounding boxes can meet in a
+// corner the circles never reach. Other shapes overlap when each one touches the other's bounding box, which is exact
+// as long as one of them is a rectangle or a point
+pub fn overlaps(lhs: &dyn QuadObject, rhs: &dyn QuadObject) -> bool {
+    if lhs.kind() == ObjectKind::Circle && rhs.kind() == ObjectKind::Circle {
+        // The bounds of a circle reach one radius past its center
+        let ((lx, ly), (rx, ry)) = (lhs.center(), rhs.center());
+        let radii = (lhs.bounds().x1 as i64 - lx as i64) + (rhs.bounds().x1 as i64 - rx as i64);
+        let (dx, dy) = (lx as i64 - rx as i64, ly as i64 - ry as i64);
+        return dx.pow(2) + dy.pow(2) <= radii.pow(2);
+    }
+    lhs.is_overlap(&rhs.bounds()) && rhs.is_overlap(&lhs.bounds())
+}
+
+// Back to front for drawing, objects with the same z keep their order
+pub fn by_z_index(objects: &[Rc<RefCell<dyn QuadObject>>]) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+    let mut sorted = objects.to_vec();
+    sorted.sort_by_key(|object| object.as_ref().borrow().z_index());
+    sorted
 }
 
 // -
 // Objects
 // -
 
+// What a boid does when it reaches a wall
+#[derive(Clone, Copy, PartialEq)]
+pub enum BoundaryMode {
+    Wrap, // Reappear at the opposite wall
+    Clamp, // Stop at the wall
+    Bounce, // Reflect the heading about the wall normal
+}
+
 // Boid
 pub struct Boid {
     id: u32,
@@ -40,15 +128,43 @@ pub struct Boid {
     y: f32,
     facing: f32,
     velocity: f32,
+    fov: f32, // Full view angle around facing, in radians
+    ttl: Option<u32>, // Frames left to live, None lives forever
+    boundary: BoundaryMode,
     red: bool,
 }
 
 impl Boid {
     pub fn new(id: u32, x: i32, y: i32, facing: f32) -> Boid {
-        Boid { id:id, x:(x as f32), y:(y as f32), facing, velocity:1.0, red:false }
+        Boid { id:id, x:(x as f32), y:(y as f32), facing, velocity:1.0, fov:BOID_FOV, ttl:None, boundary:BoundaryMode::Wrap, red:false }
     }
     pub fn new_red(id: u32, x: i32, y: i32, facing: f32) -> Boid {
-        Boid { id:id, x:(x as f32), y:(y as f32), facing, velocity:1.0, red:true }
+        Boid { id:id, x:(x as f32), y:(y as f32), facing, velocity:1.0, fov:BOID_FOV, ttl:None, boundary:BoundaryMode::Wrap, red:true }
+    }
+    pub fn with_fov(mut self, fov: f32) -> Boid {
+        self.fov = fov;
+        self
+    }
+    pub fn with_ttl(mut self, ttl: u32) -> Boid {
+        self.ttl = Some(ttl);
+        self
+    }
+    pub fn with_boundary(mut self, boundary: BoundaryMode) -> Boid {
+        self.boundary = boundary;
+        self
+    }
+
+    // Whether a point lies within the view angle, measured the same way as facing
+    fn sees(&self, x: f32, y: f32) -> bool {
+        self.faces(x, y, self.fov / 2.0)
+    }
+
+    // Whether the direction to the point is at most max_angle radians off the heading, a boid faces its own position
+    pub fn faces(&self, x: f32, y: f32, max_angle: f32) -> bool {
+        let (dx, dy) = (x - self.x, y - self.y);
+        if dx == 0.0 && dy == 0.0 { return true; }
+        let off_facing = (dx.atan2(dy) - self.facing).rem_euclid(2.0 * PI);
+        off_facing.min(2.0 * PI - off_facing) <= max_angle
     }
 }
 
@@ -56,6 +172,9 @@ impl QuadObject for Boid {
     fn get_id(&self) -> u32 {
         self.id
     }
+    fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
 
     fn draw(&self) {
         let size: f32 = 4.0;
@@ -80,40 +199,116 @@ impl QuadObject for Boid {
         draw_triangle_lines(on_circle, left_point, right_point, 1.5, YELLOW);
     }
 
+    fn svg_element(&self) -> String {
+        let size: f32 = 4.0;
+
+        let on_circle = ((self.x + ( self.facing.sin() * 2.0*size)),     (self.y + (self.facing.cos() * 2.0*size)));
+        let left_point = ((self.x + ((self.facing + PI/2.0).sin() *size)), (self.y + ((self.facing + PI/2.0).cos() * size)));
+        let right_point = ((self.x + ((self.facing - PI/2.0).sin() *size)), (self.y + ((self.facing - PI/2.0).cos() * size)));
+
+        let color = if self.red { "red" } else { "darkblue" };
+        format!("<polygon points=\"{},{} {},{} {},{}\" fill=\"none\" stroke=\"{}\"/>",
+                on_circle.0, on_circle.1, left_point.0, left_point.1, right_point.0, right_point.1, color)
+    }
+
+    // x, y and facing as f32
+    fn to_bytes(&self) -> Vec<u8> {
+        record(self.id, ObjectKind::Boid, &[self.x.to_le_bytes(), self.y.to_le_bytes(), self.facing.to_le_bytes()])
+    }
+
     fn center(&self) -> (i32, i32) {
         (self.x as i32, self.y as i32)
     }
 
+    fn bounds(&self) -> TreeSurface {
+        // A boid overlaps by its center point only
+        let (mx, my) = self.center();
+        TreeSurface { x0:mx, y0:my, x1:mx, y1:my }
+    }
+
     fn is_overlap(&self, surface: &TreeSurface) -> bool {
         let (mx, my) = self.center();
         surface.x0 <= mx && mx <= surface.x1 && surface.y0 <= my && my <= surface.y1
     }
 
+    fn velocity_vec(&self) -> (f32, f32) {
+        (self.facing.sin() * self.velocity, self.facing.cos() * self.velocity)
+    }
+
     fn update(&mut self) {
-        let (vx, vy) = (self.facing.sin(), self.facing.cos());
+        let (vx, vy) = self.velocity_vec();
         self.x += vx;
         self.y += vy;
 
         // Bounds checking
-        if self.x > 525.0 { self.x = 26.0; }
-        if self.x < 25.0 { self.x = 524.0; }
-        if self.y > 525.0 { self.y = 26.0; }
-        if self.y < 25.0 { self.y = 524.0; }
+        match self.boundary {
+            BoundaryMode::Wrap => {
+                if self.x > WALL_MAX { self.x = WALL_MIN + 1.0; }
+                if self.x < WALL_MIN { self.x = WALL_MAX - 1.0; }
+                if self.y > WALL_MAX { self.y = WALL_MIN + 1.0; }
+                if self.y < WALL_MIN { self.y = WALL_MAX - 1.0; }
+            },
+            BoundaryMode::Clamp => {
+                self.x = self.x.clamp(WALL_MIN, WALL_MAX);
+                self.y = self.y.clamp(WALL_MIN, WALL_MAX);
+            },
+            BoundaryMode::Bounce => {
+                // Heading is (sin, cos) of facing, negating facing flips x and PI - facing flips y
+                if self.x > WALL_MAX || self.x < WALL_MIN {
+                    self.x = self.x.clamp(WALL_MIN, WALL_MAX) * 2.0 - self.x;
+                    self.facing = -self.facing;
+                }
+                if self.y > WALL_MAX || self.y < WALL_MIN {
+                    self.y = self.y.clamp(WALL_MIN, WALL_MAX) * 2.0 - self.y;
+                    self.facing = PI - self.facing;
+                }
+            },
+        }
     }
 
+    fn tick_lifetime(&mut self) -> bool {
+        match self.ttl {
+            Some(0) => false,
+            Some(ttl) => { self.ttl = Some(ttl - 1); true },
+            None => true,
+        }
+    }
+
+    fn clamp_to(&mut self, surface: &TreeSurface) {
+        self.x = self.x.clamp(surface.x0 as f32, surface.x1 as f32);
+        self.y = self.y.clamp(surface.y0 as f32, surface.y1 as f32);
+    }
+
+    fn translate(&mut self, dx: i32, dy: i32) {
+        self.x += dx as f32;
+        self.y += dy as f32;
+    }
+
+    fn scale(&mut self, _factor: f32) {} // A boid is a point
+
     fn update_movement(&mut self, rhs: &Rc<RefCell<dyn QuadObject>>) {
         let boid_option = rhs.as_ref().borrow();
         match boid_option.get_boid() {
-            Some(boid) => {
+            Some(boid) if self.sees(boid.x, boid.y) => {
                 self.facing += (boid.facing - self.facing) / 5.0;
             },
-            None => {}
+            _ => {}
         }
     }
 
+    // Head straight away from the other's center, staying inside it keeps the same heading instead of flipping every frame
+    fn on_collide(&mut self, other: &dyn QuadObject) {
+        let (ox, oy) = other.center();
+        let (dx, dy) = (self.x - ox as f32, self.y - oy as f32);
+        if dx != 0.0 || dy != 0.0 { self.facing = dx.atan2(dy); }
+    }
+
     fn get_boid(&self) -> Option<&Boid> {
         return Some(self)
     }
+    fn kind(&self) -> ObjectKind {
+        ObjectKind::Boid
+    }
 }
 
 impl Display for Boid {
@@ -122,6 +317,51 @@ impl Display for Boid {
     }
 }
 
+// Boid allocations to reuse, so spawning and despawning does not allocate every time
+pub struct BoidPool {
+    free: Vec<Rc<RefCell<Boid>>>,
+    live: Vec<Rc<RefCell<Boid>>>, // Handed out by acquire, reclaimed once nothing else holds them
+    max_free: usize, // Free boids kept at most, the rest are dropped
+}
+
+impl BoidPool {
+    pub fn new(max_free: usize) -> BoidPool {
+        BoidPool { free: Vec::new(), live: Vec::new(), max_free }
+    }
+
+    // A pooled boid reset to Boid::new, or a new allocation when the pool is empty
+    pub fn acquire(&mut self, id: u32, x: i32, y: i32, facing: f32) -> Rc<RefCell<Boid>> {
+        let boid = match self.free.pop() {
+            Some(boid) => {
+                *boid.borrow_mut() = Boid::new(id, x, y, facing);
+                boid
+            },
+            None => Rc::new(RefCell::new(Boid::new(id, x, y, facing))),
+        };
+        self.live.push(Rc::clone(&boid));
+        boid
+    }
+
+    // Gives a boid back, it must not be used by the caller afterwards
+    pub fn release(&mut self, boid: Rc<RefCell<Boid>>) {
+        self.live.retain(|live| !Rc::ptr_eq(live, &boid));
+        if self.free.len() < self.max_free { self.free.push(boid); }
+    }
+
+    // Takes back every acquired boid nobody else holds anymore, e.g. after expire_dead dropped it
+    pub fn reclaim(&mut self) {
+        let (dropped, live): (Vec<_>, Vec<_>) = self.live.drain(..).partition(|boid| Rc::strong_count(boid) == 1);
+        self.live = live;
+        for boid in dropped {
+            if self.free.len() < self.max_free { self.free.push(boid); }
+        }
+    }
+
+    pub fn free_count(&self) -> usize {
+        self.free.len()
+    }
+}
+
 // Rectangle
 pub struct Rectangle {
     id: u32,
@@ -177,6 +417,9 @@ impl QuadObject for Rectangle {
     fn get_id(&self) -> u32 {
         self.id
     }
+    fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
 
     fn draw(&self) {
         let (w, h) = self.get_wh();
@@ -188,24 +431,59 @@ impl QuadObject for Rectangle {
         draw_rectangle_lines(self.x0 as f32, self.y0 as f32, w as f32, h as f32, 1.0, YELLOW);
     }
 
+    fn svg_element(&self) -> String {
+        let (w, h) = self.get_wh();
+        format!("<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"red\"/>", self.x0, self.y0, w, h)
+    }
+
+    // x0, y0, x1 and y1 as i32
+    fn to_bytes(&self) -> Vec<u8> {
+        record(self.id, ObjectKind::Rectangle, &[self.x0.to_le_bytes(), self.y0.to_le_bytes(), self.x1.to_le_bytes(), self.y1.to_le_bytes()])
+    }
+
     fn center(&self) -> (i32, i32) {
         let (w, h) = self.get_wh();
         ((w / 2) + self.x0, (h / 2) + self.y0)
     }
 
+    fn bounds(&self) -> TreeSurface {
+        self.to_tree_surface()
+    }
+
+    // Edges included like every other shape, so a rectangle on the last pixel of the tree still inserts
+    // and a zero size rectangle, like a click without drag, works as a point query
     fn is_overlap(&self, surface: &TreeSurface) -> bool {
-        if self.x0 < surface.x1 &&
-            self.x1 > surface.x0 &&
-            self.y0 < surface.y1 &&
-            self.y1 > surface.y0 {
-            true
-        } else {
-            false
-        }
+        self.to_tree_surface().intersects(surface)
     }
 
     fn update(&mut self) {}
 
+    fn clamp_to(&mut self, surface: &TreeSurface) {
+        // Shift without resizing, a rectangle larger than the surface keeps its top left inside
+        let (w, h) = self.get_wh();
+        self.x0 = max(surface.x0, min(self.x0, surface.x1 - w));
+        self.y0 = max(surface.y0, min(self.y0, surface.y1 - h));
+        self.x1 = self.x0 + w;
+        self.y1 = self.y0 + h;
+    }
+
+    fn translate(&mut self, dx: i32, dy: i32) {
+        self.x0 += dx;
+        self.x1 += dx;
+        self.y0 += dy;
+        self.y1 += dy;
+    }
+
+    fn scale(&mut self, factor: f32) {
+        let (mx, my) = self.center();
+        let (w, h) = self.get_wh();
+        let (w, h) = ((w as f32 * factor) as i32, (h as f32 * factor) as i32);
+        self.x0 = mx - w / 2;
+        self.y0 = my - h / 2;
+        self.x1 = self.x0 + w;
+        self.y1 = self.y0 + h;
+    }
+
     fn update_movement(&mut self, _rhs: &Rc<RefCell<dyn QuadObject>>) {
         return;
     }
@@ -213,6 +491,9 @@ impl QuadObject for Rectangle {
     fn get_boid(&self) -> Option<&Boid> {
         None
     }
+    fn kind(&self) -> ObjectKind {
+        ObjectKind::Rectangle
+    }
 }
 impl Display for Rectangle {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
@@ -236,6 +517,9 @@ impl QuadObject for Circle {
     fn get_id(&self) -> u32 {
         self.id
     }
+    fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
 
     fn draw(&self) {
         draw_circle_lines(self.x as f32, self.y as f32, self.radius as f32, 1.0, RED);
@@ -245,20 +529,49 @@ impl QuadObject for Circle {
         draw_circle_lines(self.x as f32, self.y as f32, self.radius as f32, 1.0, YELLOW);
     }
 
+    fn svg_element(&self) -> String {
+        format!("<circle cx=\"{}\" cy=\"{}\" r=\"{}\" fill=\"none\" stroke=\"red\"/>", self.x, self.y, self.radius)
+    }
+
+    // x, y and radius as i32
+    fn to_bytes(&self) -> Vec<u8> {
+        record(self.id, ObjectKind::Circle, &[self.x.to_le_bytes(), self.y.to_le_bytes(), self.radius.to_le_bytes()])
+    }
+
     fn center(&self) -> (i32, i32) { (self.x, self.y) }
 
+    fn bounds(&self) -> TreeSurface {
+        TreeSurface { x0:(self.x - self.radius), y0:(self.y - self.radius), x1:(self.x + self.radius), y1:(self.y + self.radius) }
+    }
+
     fn is_overlap(&self, surface: &TreeSurface) -> bool {
         let xn = max(surface.x0, min(self.x, surface.x1));
         let yn = max(surface.y0, min(self.y, surface.y1));
 
-        let dx = xn - self.x;
-        let dy = yn - self.y;
+        // Squares of pixel distances overflow i32 past about 46000
+        let dx = xn as i64 - self.x as i64;
+        let dy = yn as i64 - self.y as i64;
 
-        (dx.pow(2) + dy.pow(2)) <= self.radius.pow(2)
+        (dx.pow(2) + dy.pow(2)) <= (self.radius as i64).pow(2)
     }
 
     fn update(&mut self) {}
 
+    fn clamp_to(&mut self, surface: &TreeSurface) {
+        // Keep the whole circle inside, a circle wider than the surface keeps its left/top edge inside
+        self.x = max(surface.x0 + self.radius, min(self.x, surface.x1 - self.radius));
+        self.y = max(surface.y0 + self.radius, min(self.y, surface.y1 - self.radius));
+    }
+
+    fn translate(&mut self, dx: i32, dy: i32) {
+        self.x += dx;
+        self.y += dy;
+    }
+
+    fn scale(&mut self, factor: f32) {
+        self.radius = (self.radius as f32 * factor) as i32;
+    }
+
     fn update_movement(&mut self, _rhs: &Rc<RefCell<dyn QuadObject>>) {
         return;
     }
@@ -266,9 +579,130 @@ impl QuadObject for Circle {
     fn get_boid(&self) -> Option<&Boid> {
         None
     }
+    fn kind(&self) -> ObjectKind {
+        ObjectKind::Circle
+    }
 }
 impl Display for Circle {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "Circle")
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::f32::consts::PI;
+    use std::rc::Rc;
+    use crate::test_object::TestObject;
+    use super::{by_z_index, from_bytes, overlaps, Boid, BoidPool, BoundaryMode, Circle, ObjectKind, QuadObject, Rectangle, WALL_MAX, WALL_MIN};
+
+    #[test]
+    fn circles_overlap_by_center_distance() {
+        // About 21.2 apart, the bounding boxes meet but the circles do not
+        assert!(!overlaps(&Circle::new(0, 100, 100, 10), &Circle::new(1, 115, 115, 10)));
+        assert!(overlaps(&Circle::new(0, 100, 100, 10), &Circle::new(1, 114, 100, 5)));
+        // Touching counts
+        assert!(overlaps(&Circle::new(0, 100, 100, 10), &Circle::new(1, 115, 100, 5)));
+
+        assert!(overlaps(&Circle::new(0, 100, 100, 10), &Rectangle::new(1, 108, 95, 10, 10)));
+        assert!(!overlaps(&Circle::new(0, 100, 100, 10), &Rectangle::new(1, 108, 108, 10, 10)));
+        assert!(overlaps(&Boid::new(0, 107, 107, 0.0), &Circle::new(1, 100, 100, 10)));
+        assert!(!overlaps(&Boid::new(0, 108, 108, 0.0), &Circle::new(1, 100, 100, 10)));
+    }
+
+    #[test]
+    fn neighbour_behind_is_ignored_outside_the_fov() {
+        // Facing 0 points towards +y, so the neighbour at lower y is right behind
+        let behind: Rc<RefCell<dyn QuadObject>> = Rc::new(RefCell::new(Boid::new(1, 100, 80, 1.0)));
+        let ahead: Rc<RefCell<dyn QuadObject>> = Rc::new(RefCell::new(Boid::new(2, 100, 120, 1.0)));
+
+        let mut boid = Boid::new(0, 100, 100, 0.0).with_fov(PI * 2.0 / 3.0);
+        boid.update_movement(&behind);
+        assert_eq!(boid.facing, 0.0);
+        boid.update_movement(&ahead);
+        assert!(boid.facing > 0.0);
+
+        let mut all_round = Boid::new(0, 100, 100, 0.0).with_fov(2.0 * PI);
+        all_round.update_movement(&behind);
+        assert!(all_round.facing > 0.0);
+    }
+
+    #[test]
+    fn bounce_reverses_the_heading_at_the_right_wall() {
+        let mut boid = Boid::new(0, 525, 200, PI / 2.0).with_boundary(BoundaryMode::Bounce);
+        assert!(boid.velocity_vec().0 > 0.0);
+        boid.update();
+
+        let (vx, vy) = boid.velocity_vec();
+        assert!(vx < 0.0 && vy.abs() < 1e-3);
+        assert!((WALL_MIN..=WALL_MAX).contains(&boid.x));
+        assert_eq!(boid.y, 200.0);
+
+        // The same step under Wrap comes out at the left wall with the heading unchanged
+        let mut wrapped = Boid::new(0, 525, 200, PI / 2.0);
+        wrapped.update();
+        assert!(wrapped.velocity_vec().0 > 0.0 && wrapped.x < 100.0);
+    }
+
+    #[test]
+    fn each_shape_reports_its_kind() {
+        assert_eq!(Boid::new(0, 10, 10, 0.0).kind(), ObjectKind::Boid);
+        assert_eq!(Circle::new(1, 10, 10, 5).kind(), ObjectKind::Circle);
+        assert_eq!(Rectangle::new(2, 10, 10, 5, 5).kind(), ObjectKind::Rectangle);
+    }
+
+    #[test]
+    fn pool_reuses_released_allocations() {
+        let mut pool = BoidPool::new(2);
+        let boid = pool.acquire(0, 10, 10, 1.0);
+        let allocation = Rc::as_ptr(&boid);
+        pool.release(boid);
+        assert_eq!(pool.free_count(), 1);
+
+        // The same allocation comes back, reset to the new values
+        let reused = pool.acquire(1, 20, 30, 0.0);
+        assert_eq!(Rc::as_ptr(&reused), allocation);
+        assert_eq!((reused.borrow().get_id(), reused.borrow().center()), (1, (20, 30)));
+
+        // Dropped boids come back through reclaim, never more than max_free of them
+        let acquired: Vec<_> = (0..4).map(|id| pool.acquire(id, 0, 0, 0.0)).collect();
+        drop(acquired);
+        drop(reused);
+        pool.reclaim();
+        assert_eq!(pool.free_count(), 2);
+    }
+
+    #[test]
+    fn every_shape_round_trips_through_bytes() {
+        let shapes: Vec<Rc<RefCell<dyn QuadObject>>> = vec![
+            Rc::new(RefCell::new(Boid::new(7, 120, 340, 1.5))),
+            Rc::new(RefCell::new(Circle::new(8, -20, 60, 13))),
+            Rc::new(RefCell::new(Rectangle::new(9, 5, 6, 70, 80))),
+        ];
+        for shape in shapes.iter() {
+            let shape = shape.as_ref().borrow();
+            let copy = from_bytes(shape.kind(), &shape.to_bytes()).unwrap();
+            let copy = copy.as_ref().borrow();
+            assert_eq!((copy.get_id(), copy.kind()), (shape.get_id(), shape.kind()));
+            assert!(copy.bounds() == shape.bounds());
+            assert_eq!(copy.to_bytes(), shape.to_bytes());
+        }
+        // A record read as the wrong kind is refused
+        assert!(from_bytes(ObjectKind::Circle, &Boid::new(7, 1, 2, 0.0).to_bytes()).is_none());
+    }
+
+    #[test]
+    fn by_z_index_orders_a_mixed_list() {
+        let objects: Vec<Rc<RefCell<dyn QuadObject>>> = vec![
+            TestObject::point(0, 0, 0).with_z(2).shared(),
+            Rc::new(RefCell::new(Boid::new(1, 10, 10, 0.0))),
+            TestObject::point(2, 0, 0).with_z(-1).shared(),
+            TestObject::point(3, 0, 0).with_z(2).shared(),
+            TestObject::point(4, 0, 0).with_z(1).shared(),
+            Rc::new(RefCell::new(Boid::new(5, 20, 20, 0.0))),
+        ];
+        let order: Vec<u32> = by_z_index(&objects).iter().map(|object| object.as_ref().borrow().get_id()).collect();
+        // Background first, equal layers keep their order
+        assert_eq!(order, vec![2, 1, 5, 4, 0, 3]);
+    }
+}
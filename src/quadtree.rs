@@ -1,14 +1,17 @@
 use std::borrow::Borrow;
-use std::cell::RefCell;
-use std::fmt::{Display, Formatter};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter, Write};
 use std::rc::Rc;
 use macroquad::color::{Color, DARKGRAY, WHITE};
 use macroquad::shapes::draw_line;
 use macroquad::text::draw_text;
 use crate::{QuadObject, Rectangle};
+use crate::quad_objects::{overlaps, ObjectKind};
 
 const MAX_OBJECTS_PER_NODE: usize = 10;
 const MAX_LEAF_DEPTH: i32 = 10;
+const MIN_NODE_SIZE: i32 = 2;
 const LINE_WIDTH: f32 = 1.0;
 
 const QUAD_LINES_COLOR: Color = WHITE;
@@ -16,55 +19,582 @@ const QUAD_LINES_COLOR: Color = WHITE;
 // --------------------
 // Object bounds to grid coordinates
 // --------------------
-fn assign_object_to_grid(surface: &TreeSurface, object: &Rc<RefCell<dyn QuadObject>>) -> Vec<i32> {
+pub fn assign_object_to_grid(surface: &TreeSurface, depth: i32, fanout: usize, object: &dyn QuadObject) -> Vec<usize> {
+    assign_object_to_cells(surface, depth, (fanout, fanout), object)
+}
+
+// Same for a columns x rows split, the indices are row major
+pub fn assign_object_to_cells(surface: &TreeSurface, depth: i32, (columns, rows): (usize, usize), object: &dyn QuadObject) -> Vec<usize> {
+    let (xs, ys) = surface.cell_bounds_at_depth(depth, columns, rows); // Define split points, same as switch_object_to_leaves
+    assign_object_to_bounds(&xs, &ys, object)
+}
+
+// Same for given split points, columns + 1 of them in xs and rows + 1 in ys
+fn assign_object_to_bounds(xs: &[i32], ys: &[i32], object: &dyn QuadObject) -> Vec<usize> {
     let mut result_vec = Vec::new();
-    let (mx, my) = surface.mxy(); // Define split points
-    let object = object.as_ref().borrow(); // Get object
-
-    if object.is_overlap(&TreeSurface::from_size(surface.x0, surface.y0, mx - 1, my - 1)) {
-        result_vec.push(0) }
-    if object.is_overlap(&TreeSurface::from_size(mx, surface.y0, surface.x1, my - 1)) {
-        result_vec.push(1) }
-    if object.is_overlap(&TreeSurface::from_size(surface.x0, my, mx - 1, surface.y1)) {
-        result_vec.push(2) }
-    if object.is_overlap(&TreeSurface::from_size(mx, my, surface.x1, surface.y1)) {
-        result_vec.push(3) }
+    let (columns, rows) = (xs.len() - 1, ys.len() - 1);
+    let bounds = object.bounds();
+
+    // Only the rows and columns the bounding box reaches can overlap, the shape decides within those
+    let reached_columns: Vec<usize> = (0..columns).filter(|&col| xs[col] <= bounds.x1 && bounds.x0 < xs[col + 1]).collect();
+    for row in (0..rows).filter(|&row| ys[row] <= bounds.y1 && bounds.y0 < ys[row + 1]) {
+        for &col in reached_columns.iter() {
+            let cell = TreeSurface::from_size(xs[col], ys[row], xs[col + 1] - 1, ys[row + 1] - 1);
+            if object.is_overlap(&cell) {
+                result_vec.push(row * columns + col) }
+        }
+    }
 
     result_vec
 }
 
+// --------------------
+// Straddling objects live in several leaves, keep the first of each id
+// --------------------
+fn dedup_by_id(objects: Vec<Rc<RefCell<dyn QuadObject>>>) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+    let mut seen = HashSet::new();
+    objects.into_iter().filter(|object| seen.insert(object.as_ref().borrow().get_id())).collect()
+}
+
 // --------------------
 // QuadTree
 // --------------------
-pub struct QuadTree {
-    top_node: Box<TreeNode>,
-    surface: TreeSurface,
+pub struct QuadTree<D: Default + Clone = ()> {
+    top_node: Box<TreeNode<D>>,
+    config: TreeConfig,
+
+    centers: HashMap<u32, (i32, i32)>, // Center of each object when it was last (re)inserted
+    generation: u64, // Bumped by every mutation, lets cached query results detect they are stale
+    max_speed: f32, // Largest velocity_vec length seen by insert_object or update_object since the last clear, bounds query_swept
+    on_object_moved: Option<MovedCallback>,
+
+    regions: Option<Box<QuadTree>>, // Labeled static areas, in a tree of their own so object queries never see them
+    region_labels: Vec<String>, // Label of each region by id
+    query_stats: QueryStats, // Updated by queries through &self
+}
+
+// Called with (id, old center, new center)
+pub type MovedCallback = Box<dyn FnMut(u32, (i32, i32), (i32, i32))>;
+
+// Area and tuning parameters of a tree, enough to rebuild it with from_config
+#[derive(Clone, Copy)]
+pub struct TreeConfig {
+    pub surface: TreeSurface,
+    pub capacity: usize, // Total QuadObject::weight a node holds before it splits
+    pub capacity_scaling: CapacityScaling,
+    pub max_depth: i32, // Nodes at this depth never split
+    pub min_node_size: i32, // Nodes whose children would be narrower or shorter than this many pixels never split
+    pub fanout: usize, // A split makes a fanout x fanout grid of children, 2 is a quadtree
+    pub insert_policy: InsertPolicy,
+    pub sorted_leaves: bool, // Keep leaf objects sorted by the right edge of their bounds, queries binary search past the left side
+    pub out_of_bounds: OutOfBoundsPolicy,
+    pub max_nodes: Option<usize>, // Node budget, once a split would exceed it nodes keep objects past capacity
+    pub split_mode: SplitMode,
+}
+
+// How the capacity of a node depends on its depth
+#[derive(Clone, Copy, PartialEq)]
+pub enum CapacityScaling {
+    Constant, // Every node holds capacity
+    Halving, // The root holds capacity, each level below half as much, at least 1
+}
+
+// How a node divides its surface when it splits
+#[derive(Clone, Copy, PartialEq)]
+pub enum SplitMode {
+    Quad, // A fanout x fanout grid
+    Alternating, // Two halves, across x at even depths and across y at odd depths, for worlds stretched along one axis
+}
+
+impl TreeConfig {
+    // Capacity of a node at this depth, the root is at depth 1
+    pub fn capacity_at_depth(&self, depth: i32) -> usize {
+        match self.capacity_scaling {
+            CapacityScaling::Constant => self.capacity,
+            CapacityScaling::Halving => self.capacity.checked_shr((depth - 1).max(0) as u32).unwrap_or(0).max(1),
+        }
+    }
+
+    // Columns and rows a node at this depth splits into
+    pub(crate) fn split_grid(&self, depth: i32) -> (usize, usize) {
+        match self.split_mode {
+            SplitMode::Quad => (self.fanout, self.fanout),
+            SplitMode::Alternating if depth % 2 == 0 => (2, 1),
+            SplitMode::Alternating => (1, 2),
+        }
+    }
+
+    // Children made by one split
+    fn split_children(&self) -> usize {
+        let (columns, rows) = self.split_grid(0);
+        columns * rows
+    }
+
+    // Whether a leaf holding objects of the stored weights goes over capacity when one of the incoming weight arrives
+    // A lone object stays put however heavy, splitting can not separate it from nothing and would only copy it down
+    pub(crate) fn over_capacity(&self, depth: i32, stored: impl ExactSizeIterator<Item = u32>, incoming: u32) -> bool {
+        let count = stored.len();
+        let weight: usize = stored.map(|weight| weight as usize).sum();
+        count > 0 && weight + incoming as usize > self.capacity_at_depth(depth)
+    }
+
+    // Whether a node may split at all, checked once it is over capacity
+    pub(crate) fn can_split(&self, depth: i32, surface: &TreeSurface, nodes_left: usize) -> bool {
+        // Every child needs at least min_node_size pixels per side
+        let (columns, rows) = self.split_grid(depth);
+        let min_size = self.min_node_size.max(1) as i64;
+        let (width, height) = (surface.x1 as i64 - surface.x0 as i64 + 1, surface.y1 as i64 - surface.y0 as i64 + 1);
+        let valid_size = width >= min_size * columns as i64 && height >= min_size * rows as i64;
+        // Below max depth, and the node budget allows the new children
+        valid_size && depth < self.max_depth && nodes_left >= columns * rows
+    }
+}
+
+// What insert_object does with an object that does not overlap the tree surface
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutOfBoundsPolicy {
+    Drop, // Ignore it
+    Clamp, // Move it inside with QuadObject::clamp_to, then insert
+    Grow, // Grow the tree until it reaches the object's center, then insert
+    Error, // Return Err(OutOfBounds)
+}
+
+// Returned by insert_object under OutOfBoundsPolicy::Error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutOfBounds {
+    pub id: u32,
+    pub center: (i32, i32),
+}
+
+impl Display for OutOfBounds {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "object {} at {:?} is outside the tree", self.id, self.center)
+    }
+}
+
+// Where an object that overlaps several children of a node is stored
+#[derive(Clone, Copy, PartialEq)]
+pub enum InsertPolicy {
+    Duplicate, // In every child it overlaps, queries deduplicate
+    Enclosing, // Once, at the deepest node whose surface contains its bounds
+}
+
+// Which leaves around the leaves of an object count as its neighbourhood
+#[derive(Clone, Copy, PartialEq)]
+pub enum Adjacency {
+    SameLeaf, // Only the leaves holding the object
+    FourWay, // Plus the leaves sharing an edge with them
+    EightWay, // Plus the leaves touching them, diagonals included
+}
+
+impl Default for TreeConfig {
+    fn default() -> TreeConfig {
+        TreeConfig { surface: TreeSurface::from_size(0, 0, 0, 0), capacity: MAX_OBJECTS_PER_NODE, capacity_scaling: CapacityScaling::Constant, max_depth: MAX_LEAF_DEPTH, min_node_size: MIN_NODE_SIZE, fanout: 2, insert_policy: InsertPolicy::Duplicate, sorted_leaves: false, out_of_bounds: OutOfBoundsPolicy::Drop, max_nodes: None, split_mode: SplitMode::Quad }
+    }
 }
 
-impl Display for QuadTree {
+impl<D: Default + Clone> Display for QuadTree<D> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "Quadtree: {}", self.top_node)
     }
 }
 impl QuadTree {
     pub fn new(x0: i32, y0: i32, width: i32, height: i32) -> QuadTree {
-        let surface = TreeSurface { x0, y0, x1:(x0+width), y1:(y0+height) };
-
-        QuadTree {
-            top_node: Box::new(TreeNode::new(1, surface.x0, surface.y0, surface.x1, surface.y1)),
-            surface,
+        QuadTree::builder().bounds(x0, y0, width, height).build()
+    }
+    pub fn with_capacity(x0: i32, y0: i32, width: i32, height: i32, capacity: usize) -> QuadTree {
+        QuadTree::builder().bounds(x0, y0, width, height).capacity(capacity).build()
+    }
+    // A tree with the same area and tuning as the config, holding the given objects
+    // Objects the out of bounds policy rejects are left out
+    pub fn from_config(config: TreeConfig, objects: &[Rc<RefCell<dyn QuadObject>>]) -> QuadTree {
+        let mut tree = QuadTreeBuilder { config }.build();
+        for object in objects.iter() {
+            let _ = tree.insert_object(Rc::clone(object));
         }
+        tree
+    }
+    pub fn builder() -> QuadTreeBuilder {
+        QuadTreeBuilder { config: TreeConfig::default() }
     }
+}
+impl<D: Default + Clone> QuadTree<D> {
     pub fn clear(&mut self) {
+        self.generation += 1;
+        self.centers.clear();
+        self.max_speed = 0.0;
         self.top_node.clear();
-        self.top_node = Box::new(TreeNode::new(1, self.surface.x0, self.surface.y0, self.surface.x1, self.surface.y1));
+        self.top_node = Box::new(TreeNode::new(1, self.config.surface.x0, self.config.surface.y0, self.config.surface.x1, self.config.surface.y1));
     }
     pub fn get_surface(&self) -> &TreeSurface {
-        &self.surface
+        &self.config.surface
+    }
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+    pub fn config(&self) -> TreeConfig {
+        self.config
+    }
+    pub fn capacity(&self) -> usize {
+        self.config.capacity
+    }
+    pub fn max_depth(&self) -> i32 {
+        self.config.max_depth
+    }
+    pub fn fanout(&self) -> usize {
+        self.config.fanout
+    }
+    pub fn insert_policy(&self) -> InsertPolicy {
+        self.config.insert_policy
+    }
+    pub fn out_of_bounds(&self) -> OutOfBoundsPolicy {
+        self.config.out_of_bounds
+    }
+
+    // Objects outside the surface are handled by the out of bounds policy, only OutOfBoundsPolicy::Error returns Err
+    pub fn insert_object(&mut self, object: Rc<RefCell<dyn QuadObject>>) -> Result<(), OutOfBounds> {
+        if !object.as_ref().borrow().is_overlap(&self.config.surface) {
+            match self.config.out_of_bounds {
+                OutOfBoundsPolicy::Drop => return Ok(()),
+                OutOfBoundsPolicy::Clamp => object.as_ref().borrow_mut().clamp_to(&self.config.surface),
+                OutOfBoundsPolicy::Grow => {
+                    let (cx, cy) = object.as_ref().borrow().center();
+                    self.grow_to_fit(cx, cy);
+                },
+                OutOfBoundsPolicy::Error => {
+                    let object = object.as_ref().borrow();
+                    return Err(OutOfBounds { id: object.get_id(), center: object.center() });
+                },
+            }
+        }
+
+        self.generation += 1;
+        let (id, center) = { let object = object.as_ref().borrow(); (object.get_id(), object.center()) };
+        self.centers.insert(id, center);
+        self.track_speed(&object);
+        self.top_node.insert_object(object, &self.config, &mut self.nodes_left());
+        Ok(())
+    }
+
+    // Speeds only ever raise the bound, a removed or slowed down object keeps it until clear()
+    fn track_speed(&mut self, object: &Rc<RefCell<dyn QuadObject>>) {
+        let (vx, vy) = object.as_ref().borrow().velocity_vec();
+        self.max_speed = self.max_speed.max(vx.hypot(vy));
+    }
+
+    // Nodes that may still be created before reaching max_nodes
+    fn nodes_left(&self) -> usize {
+        match self.config.max_nodes {
+            Some(max_nodes) => max_nodes.saturating_sub(self.node_count() as usize),
+            None => usize::MAX,
+        }
+    }
+
+    // Removes every copy of the object from the leaves, returns whether it was present
+    pub fn remove_object(&mut self, id: u32) -> bool {
+        self.generation += 1;
+        self.centers.remove(&id);
+        self.top_node.remove_object(id)
+    }
+
+    // Removes every object with one of the ids in a single descent, emptied subtrees collapse on the way back up
+    pub fn remove_many(&mut self, ids: &[u32]) {
+        let ids: HashSet<u32> = ids.iter().copied().collect();
+        self.generation += 1;
+        self.centers.retain(|id, _| !ids.contains(id));
+        self.top_node.remove_many(&ids);
+    }
+
+    // Removes and returns every object overlapping the region, in one descent instead of a query and a removal per id
+    pub fn remove_in(&mut self, region: &Rectangle) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let region = region.to_tree_surface();
+        let mut removed = vec![];
+        self.generation += 1;
+        self.top_node.remove_in(&region, &mut removed);
+
+        let removed = dedup_by_id(removed);
+        for object in removed.iter() {
+            let (id, bounds) = { let object = object.as_ref().borrow(); (object.get_id(), object.bounds()) };
+            self.centers.remove(&id);
+            // Copies in leaves clear of the region were not visited by the descent
+            if !region.contains(&bounds) { self.top_node.remove_object(id); }
+        }
+        removed
+    }
+
+    // Collapses subtrees holding no more than capacity back into leaves and releases spare Vec capacity, after a burst of removals
+    pub fn shrink_to_fit(&mut self) {
+        self.top_node.shrink_to_fit(&self.config);
+    }
+
+    // Empties the region and inserts the given objects, which should be the ones now in it, the rest of the tree is left alone
+    // Objects reaching outside the region stay when they are not among the given objects, so the unchanged side keeps them
+    pub fn rebuild_region(&mut self, region: &Rectangle, objects: &[Rc<RefCell<dyn QuadObject>>]) {
+        let region_surface = region.to_tree_surface();
+        let given: HashSet<u32> = objects.iter().map(|object| object.as_ref().borrow().get_id()).collect();
+        for object in self.remove_in(region) {
+            let (id, bounds) = { let object = object.as_ref().borrow(); (object.get_id(), object.bounds()) };
+            if !given.contains(&id) && !region_surface.contains(&bounds) { let _ = self.insert_object(object); }
+        }
+        for object in objects.iter() {
+            let _ = self.insert_object(Rc::clone(object));
+        }
+    }
+
+    // Reinserts an object after it moved so it lands in the leaves matching its new position
+    pub fn update_object(&mut self, object: &Rc<RefCell<dyn QuadObject>>) {
+        let (id, new_center) = { let object = object.as_ref().borrow(); (object.get_id(), object.center()) };
+        let old_center = self.centers.get(&id).copied().unwrap_or(new_center);
+
+        let mut old_leaves = vec![];
+        self.top_node.leaves_holding(id, &mut old_leaves);
+        self.generation += 1;
+        self.top_node.remove_object(id);
+        self.track_speed(object);
+        self.top_node.insert_object(Rc::clone(object), &self.config, &mut self.nodes_left());
+        self.centers.insert(id, new_center);
+
+        if let Some(on_object_moved) = self.on_object_moved.as_mut() {
+            let mut new_leaves = vec![];
+            self.top_node.leaves_holding(id, &mut new_leaves);
+            if old_leaves != new_leaves {
+                on_object_moved(id, old_center, new_center);
+            }
+        }
+    }
+
+    // Swaps the object with the given id for new_object, which should carry the same id
+    // Returns whether the original was present, new_object is inserted either way
+    pub fn replace(&mut self, id: u32, new_object: Rc<RefCell<dyn QuadObject>>) -> bool {
+        let existed = self.remove_object(id);
+        let _ = self.insert_object(new_object);
+        existed
+    }
+
+    // Moves the object so its center lands on new_center, reindexes it and returns the others within radius of it
+    pub fn move_and_query(&mut self, object: &Rc<RefCell<dyn QuadObject>>, new_center: (i32, i32), radius: i32) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let id = {
+            let mut object = object.as_ref().borrow_mut();
+            let (cx, cy) = object.center();
+            object.translate(new_center.0 - cx, new_center.1 - cy);
+            object.get_id()
+        };
+        self.update_object(object);
+
+        let mut query_result = vec![];
+        let nodes_visited = self.top_node.query_by_radius(new_center.0, new_center.1, radius as f32, &mut query_result);
+        let query_result: Vec<Rc<RefCell<dyn QuadObject>>> = dedup_by_id(query_result).into_iter().filter(|other| other.as_ref().borrow().get_id() != id).collect();
+        self.record_query(query_result.len(), nodes_visited);
+        query_result
+    }
+
+    // Moves every object back inside the tree surface and reindexes it
+    pub fn clamp_objects(&mut self, objects: &mut [Rc<RefCell<dyn QuadObject>>]) {
+        for object in objects.iter() {
+            object.as_ref().borrow_mut().clamp_to(&self.config.surface);
+            self.update_object(object);
+        }
+    }
+
+    // Called with (id, old center, new center) whenever update_object moves an object to other leaves
+    pub fn set_on_object_moved(&mut self, on_object_moved: Option<MovedCallback>) {
+        self.on_object_moved = on_object_moved;
+    }
+
+    // Adds a labeled area, like a spawn zone, for regions_at. Regions are kept through clear()
+    pub fn register_region(&mut self, mut rect: Rectangle, label: String) {
+        let surface = self.config.surface;
+        let regions = self.regions.get_or_insert_with(|| {
+            let config = TreeConfig { surface, out_of_bounds: OutOfBoundsPolicy::Grow, ..TreeConfig::default() };
+            Box::new(QuadTreeBuilder { config }.build())
+        });
+        rect.set_id(self.region_labels.len() as u32);
+        let _ = regions.insert_object(Rc::new(RefCell::new(rect)));
+        self.region_labels.push(label);
+    }
+
+    // Labels of the regions covering the point, edges included, in registration order
+    pub fn regions_at(&self, x: i32, y: i32) -> Vec<&str> {
+        let Some(regions) = self.regions.as_ref() else { return vec![]; };
+        let mut ids: Vec<u32> = regions.query_point(x, y).iter().map(|region| region.as_ref().borrow().get_id()).collect();
+        ids.sort_unstable();
+        ids.iter().map(|&id| self.region_labels[id as usize].as_str()).collect()
+    }
+
+    // Every object in the tree once, in traversal order
+    fn unique_objects(&self) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut objects = vec![];
+        self.top_node.collect_objects(&mut objects);
+        dedup_by_id(objects)
+    }
+
+    // Inserts every object and returns the ids of those that ended up in no node, e.g. dropped as out of bounds
+    pub fn build_reporting(&mut self, objects: &[Rc<RefCell<dyn QuadObject>>]) -> Vec<u32> {
+        let mut missing = vec![];
+        for object in objects.iter() {
+            let _ = self.insert_object(Rc::clone(object));
+            if !self.top_node.contains_object(object) { missing.push(object.as_ref().borrow().get_id()); }
+        }
+        missing
+    }
+
+    // Reassigns ids 0..n in slice order and rebuilds the tree from the slice
+    pub fn compact_ids(&mut self, objects: &mut [Rc<RefCell<dyn QuadObject>>]) {
+        self.clear();
+        for (id, object) in objects.iter().enumerate() {
+            object.as_ref().borrow_mut().set_id(id as u32);
+            let _ = self.insert_object(Rc::clone(object));
+        }
+    }
+
+    // Inserts the objects of another tree, growing to cover its surface, ids already present are skipped
+    pub fn merge(&mut self, other: QuadTree<D>) {
+        self.grow_to_fit(other.config.surface.x0, other.config.surface.y0);
+        self.grow_to_fit(other.config.surface.x1, other.config.surface.y1);
+        for object in other.unique_objects() {
+            let id = object.as_ref().borrow().get_id();
+            if !self.centers.contains_key(&id) { let _ = self.insert_object(object); }
+        }
+    }
+
+    // Grows the surface towards the point until it is inside. Every step re-roots: the old root becomes a corner
+    // child of a root fanout times its size, keeping its subtree and data one level deeper
+    pub fn grow_to_fit(&mut self, x: i32, y: i32) {
+        while !self.config.surface.contains(&TreeSurface { x0: x, y0: y, x1: x, y1: y }) {
+            if !self.re_root_towards(x, y) {
+                self.grow_by_rebuild(x, y);
+                return;
+            }
+        }
+    }
+
+    // One growth step, false when the old root can not become a child as it is
+    fn re_root_towards(&mut self, x: i32, y: i32) -> bool {
+        let fanout = self.config.fanout;
+        // Alternating splits change direction with the depth, and the deepest nodes would end up past max_depth
+        if self.config.split_mode != SplitMode::Quad || self.top_node.deepest_node() >= self.config.max_depth { return false; }
+        if self.nodes_left() < fanout * fanout { return false; }
+
+        // Low or high side of one axis, with the index of the old root along it
+        let grow = |lo: i32, hi: i32, towards_low: bool| {
+            let extra = (hi as i64 - lo as i64 + 1) * (fanout as i64 - 1);
+            let (lo, hi, index) = if towards_low { (lo as i64 - extra, hi as i64, fanout - 1) } else { (lo as i64, hi as i64 + extra, 0) };
+            // The split of a surface ending at i32::MAX is clamped, so the old root would no longer be an exact cell
+            (lo >= i32::MIN as i64 && hi < i32::MAX as i64).then_some((lo as i32, hi as i32, index))
+        };
+        let old = self.config.surface;
+        let (Some((x0, x1, col)), Some((y0, y1, row))) = (grow(old.x0, old.x1, x < old.x0), grow(old.y0, old.y1, y < old.y0)) else { return false };
+
+        let mut old_root = Some(std::mem::replace(&mut self.top_node, Box::new(TreeNode::new(1, x0, y0, x1, y1))));
+        let (xs, ys) = self.top_node.surface.grid_bounds_at_depth(1, fanout);
+        for r in 0..fanout {
+            for c in 0..fanout {
+                if (r, c) == (row, col) {
+                    let mut moved = *old_root.take().unwrap();
+                    moved.deepen();
+                    self.top_node.leaves.push(moved);
+                } else {
+                    self.top_node.leaves.push(TreeNode::new(2, xs[c], ys[r], xs[c + 1] - 1, ys[r + 1] - 1));
+                }
+            }
+        }
+        debug_assert!(self.top_node.leaves[row * fanout + col].surface == old, "re-rooted surface does not split back into the old root");
+        self.top_node.objects = None;
+        self.top_node.content_bounds = self.top_node.leaves[row * fanout + col].content_bounds;
+        self.config.surface = self.top_node.surface;
+        self.generation += 1;
+        true
+    }
+
+    // Doubles the surface towards the point until it is inside, then rebuilds around the existing objects, which resets the node data
+    fn grow_by_rebuild(&mut self, x: i32, y: i32) {
+        // Saturating, a surface reaching the end of the i32 range stops there instead of wrapping around
+        let span = |lo: i32, hi: i32| (hi as i64 - lo as i64 + 1).min(i32::MAX as i64) as i32;
+        let surface = &mut self.config.surface;
+        while x < surface.x0 { surface.x0 = surface.x0.saturating_sub(span(surface.x0, surface.x1)); }
+        while x > surface.x1 { surface.x1 = surface.x1.saturating_add(span(surface.x0, surface.x1)); }
+        while y < surface.y0 { surface.y0 = surface.y0.saturating_sub(span(surface.y0, surface.y1)); }
+        while y > surface.y1 { surface.y1 = surface.y1.saturating_add(span(surface.y0, surface.y1)); }
+
+        let objects = self.unique_objects();
+        self.clear();
+        // Everything was inside the old surface, so nothing can be rejected
+        for object in objects {
+            let _ = self.insert_object(object);
+        }
     }
+}
+
+// --------------------
+// QuadTreeBuilder
+// --------------------
+pub struct QuadTreeBuilder {
+    config: TreeConfig,
+}
 
-    pub fn insert_object(&mut self, object: Rc<RefCell<dyn QuadObject>>) {
-        self.top_node.insert_object(object);
+impl QuadTreeBuilder {
+    pub fn bounds(mut self, x0: i32, y0: i32, width: i32, height: i32) -> QuadTreeBuilder {
+        self.config.surface = TreeSurface::from_origin(x0, y0, width, height);
+        self
+    }
+    pub fn capacity(mut self, capacity: usize) -> QuadTreeBuilder {
+        self.config.capacity = capacity;
+        self
+    }
+    pub fn capacity_scaling(mut self, capacity_scaling: CapacityScaling) -> QuadTreeBuilder {
+        self.config.capacity_scaling = capacity_scaling;
+        self
+    }
+    pub fn max_depth(mut self, max_depth: i32) -> QuadTreeBuilder {
+        self.config.max_depth = max_depth;
+        self
+    }
+    pub fn min_node_size(mut self, min_node_size: i32) -> QuadTreeBuilder {
+        self.config.min_node_size = min_node_size;
+        self
+    }
+    pub fn max_nodes(mut self, max_nodes: usize) -> QuadTreeBuilder {
+        self.config.max_nodes = Some(max_nodes);
+        self
+    }
+    pub fn out_of_bounds(mut self, out_of_bounds: OutOfBoundsPolicy) -> QuadTreeBuilder {
+        self.config.out_of_bounds = out_of_bounds;
+        self
+    }
+    pub fn sorted_leaves(mut self, sorted_leaves: bool) -> QuadTreeBuilder {
+        self.config.sorted_leaves = sorted_leaves;
+        self
+    }
+    pub fn insert_policy(mut self, insert_policy: InsertPolicy) -> QuadTreeBuilder {
+        self.config.insert_policy = insert_policy;
+        self
+    }
+    pub fn split_mode(mut self, split_mode: SplitMode) -> QuadTreeBuilder {
+        self.config.split_mode = split_mode;
+        self
+    }
+    pub fn fanout(mut self, fanout: usize) -> QuadTreeBuilder {
+        assert!(fanout >= 2, "a node must split into at least 2x2 children");
+        self.config.fanout = fanout;
+        self
+    }
+    pub fn build(self) -> QuadTree {
+        self.build_with_data()
+    }
+    // A tree carrying a D in every node, see QuadTree::for_each_node
+    pub fn build_with_data<D: Default + Clone>(self) -> QuadTree<D> {
+        let surface = self.config.surface;
+        QuadTree {
+            top_node: Box::new(TreeNode::new(1, surface.x0, surface.y0, surface.x1, surface.y1)),
+            config: self.config,
+            centers: HashMap::new(),
+            generation: 0,
+            max_speed: 0.0,
+            on_object_moved: None,
+            regions: None,
+            region_labels: Vec::new(),
+            query_stats: QueryStats::default(),
+        }
     }
 }
 
@@ -72,6 +602,7 @@ impl QuadTree {
 // --------------------
 // TreeSurface
 // --------------------
+#[derive(Clone, Copy, PartialEq)]
 pub struct TreeSurface {
     pub x0: i32, pub y0: i32, pub x1: i32, pub y1: i32, // Defining topleft with o and bottomright with i
 }
@@ -80,15 +611,122 @@ impl TreeSurface {
     pub fn from_size(x0: i32, y0: i32, x1: i32, y1: i32) -> TreeSurface {
         TreeSurface { x0, y0, x1, y1}
     }
+    // A surface from its corner and extent, ending at the edge of the i32 range instead of wrapping past it
+    pub fn from_origin(x0: i32, y0: i32, width: i32, height: i32) -> TreeSurface {
+        TreeSurface { x0, y0, x1: x0.saturating_add(width), y1: y0.saturating_add(height) }
+    }
+    // Split points: the halves are [x0, mx - 1] and [mx, x1], which differ by at most one pixel
+    // An odd leftover pixel goes to the right/bottom half
     pub fn mx(&self) -> i32 {
-        ((self.x1 - self.x0) / 2) + self.x0
+        split_point(self.x0, self.x1, false)
     }
     pub fn my(&self) -> i32 {
-        ((self.y1 - self.y0) / 2) + self.y0
+        split_point(self.y0, self.y1, false)
     }
     pub fn mxy(&self) -> (i32, i32) {
         (self.mx(), self.my())
     }
+    // Split points used by the tree, the leftover pixel alternates sides per depth so repeated splits don't drift
+    pub fn mxy_at_depth(&self, depth: i32) -> (i32, i32) {
+        let remainder_first = depth % 2 == 1;
+        (split_point(self.x0, self.x1, remainder_first), split_point(self.y0, self.y1, remainder_first))
+    }
+    // Cell boundaries of a fanout x fanout split, cell i spans [xs[i], xs[i + 1] - 1] and likewise for ys
+    // Cells differ by at most one pixel and leftover pixels alternate sides per depth like mxy_at_depth
+    pub fn grid_bounds_at_depth(&self, depth: i32, fanout: usize) -> (Vec<i32>, Vec<i32>) {
+        self.cell_bounds_at_depth(depth, fanout, fanout)
+    }
+    // Same for a columns x rows split
+    pub fn cell_bounds_at_depth(&self, depth: i32, columns: usize, rows: usize) -> (Vec<i32>, Vec<i32>) {
+        let remainder_first = depth % 2 == 1;
+        (grid_bounds(self.x0, self.x1, columns, remainder_first), grid_bounds(self.y0, self.y1, rows, remainder_first))
+    }
+    // Pixels covered, edges inclusive
+    pub fn area(&self) -> i64 {
+        (self.x1 as i64 - self.x0 as i64 + 1).max(0) * (self.y1 as i64 - self.y0 as i64 + 1).max(0)
+    }
+    // Smallest surface covering both
+    pub fn union(&self, other: &TreeSurface) -> TreeSurface {
+        TreeSurface { x0: self.x0.min(other.x0), y0: self.y0.min(other.y0), x1: self.x1.max(other.x1), y1: self.y1.max(other.y1) }
+    }
+    pub fn intersection(&self, other: &TreeSurface) -> TreeSurface {
+        TreeSurface { x0: self.x0.max(other.x0), y0: self.y0.max(other.y0), x1: self.x1.min(other.x1), y1: self.y1.min(other.y1) }
+    }
+    // Empty, e.g. the intersection of surfaces that do not touch
+    pub fn is_degenerate(&self) -> bool {
+        self.x1 < self.x0 || self.y1 < self.y0
+    }
+    // Both surfaces are inclusive on all edges
+    pub fn contains(&self, other: &TreeSurface) -> bool {
+        self.x0 <= other.x0 && other.x1 <= self.x1 && self.y0 <= other.y0 && other.y1 <= self.y1
+    }
+    pub fn intersects(&self, other: &TreeSurface) -> bool {
+        self.x0 <= other.x1 && other.x0 <= self.x1 && self.y0 <= other.y1 && other.y0 <= self.y1
+    }
+    // Euclidean distance from a point to the closest point of the surface, 0 inside
+    pub fn distance_to(&self, x: i32, y: i32) -> f32 {
+        let (x, y) = (x as i64, y as i64);
+        let dx = (self.x0 as i64 - x).max(0).max(x - self.x1 as i64);
+        let dy = (self.y0 as i64 - y).max(0).max(y - self.y1 as i64);
+        ((dx as f32).powi(2) + (dy as f32).powi(2)).sqrt()
+    }
+    // Position relative to the surface, (x0, y0) maps to (0, 0) and (x1, y1) to (1, 1)
+    pub fn normalize_point(&self, x: i32, y: i32) -> (f32, f32) {
+        let scale = |value: i32, lo: i32, hi: i32| if hi == lo { 0.0 } else { ((value as f64 - lo as f64) / (hi as f64 - lo as f64)) as f32 };
+        (scale(x, self.x0, self.x1), scale(y, self.y0, self.y1))
+    }
+}
+
+// Start of the second half when splitting the inclusive range [lo, hi] in two
+// Worked out in i64, the length of a range can exceed i32::MAX
+fn split_point(lo: i32, hi: i32, remainder_first: bool) -> i32 {
+    (lo as i64 + (hi as i64 - lo as i64 + 1 + remainder_first as i64) / 2) as i32
+}
+
+// Start of each part when splitting the inclusive range [lo, hi] in `parts`, followed by hi + 1
+// For two parts the middle boundary is split_point
+fn grid_bounds(lo: i32, hi: i32, parts: usize, remainder_first: bool) -> Vec<i32> {
+    let (n, parts) = (hi as i64 - lo as i64 + 1, parts as i64);
+    let rounding = if remainder_first { parts - 1 } else { 0 };
+    // The last boundary is hi + 1, which does not fit an i32 for a range ending at i32::MAX
+    (0..=parts).map(|k| (lo as i64 + (n * k + rounding) / parts).min(i32::MAX as i64) as i32).collect()
+}
+
+// Euclidean distance between two points, the metric for every distance based query
+pub fn point_distance((ax, ay): (i32, i32), (bx, by): (i32, i32)) -> f32 {
+    (((ax as i64 - bx as i64) as f32).powi(2) + ((ay as i64 - by as i64) as f32).powi(2)).sqrt()
+}
+
+// Convex hull with Andrew's monotone chain, counter clockwise with y pointing up, collinear points left out
+fn convex_hull(mut points: Vec<(i32, i32)>) -> Vec<(i32, i32)> {
+    points.sort_unstable();
+    points.dedup();
+    if points.len() < 3 { return points; }
+
+    // Differences of i32 take 33 bits, their products up to 66, so the whole range needs i128
+    let cross = |o: (i32, i32), a: (i32, i32), b: (i32, i32)| {
+        (a.0 as i128 - o.0 as i128) * (b.1 as i128 - o.1 as i128) - (a.1 as i128 - o.1 as i128) * (b.0 as i128 - o.0 as i128)
+    };
+    let mut hull: Vec<(i32, i32)> = vec![];
+    // Lower chain left to right, then the upper chain back, each point kept only while it turns left
+    for pass in [points.clone(), points.into_iter().rev().collect()] {
+        let start = hull.len();
+        for point in pass {
+            while hull.len() >= start + 2 && cross(hull[hull.len() - 2], hull[hull.len() - 1], point) <= 0 {
+                hull.pop();
+            }
+            hull.push(point);
+        }
+        // The last point of a chain starts the next one
+        hull.pop();
+    }
+    hull
+}
+
+// Z-order code of a point, the bits of x on even and of y on odd positions
+fn morton_code(x: u32, y: u32) -> u64 {
+    let spread = |value: u32| (0..32).fold(0u64, |code, bit| code | (((value as u64 >> bit) & 1) << (2 * bit)));
+    spread(x) | (spread(y) << 1)
 }
 
 impl Display for TreeSurface {
@@ -100,148 +738,441 @@ impl Display for TreeSurface {
 // --------------------
 // TreeNode
 // --------------------
-struct TreeNode {
+struct TreeNode<D: Default + Clone> {
     depth: i32,
     surface: TreeSurface,
+    // User data, D::default() in a new root and in the empty nodes grow_to_fit adds, a split hands every child a copy of
+    // the parent's. Collapsing a subtree keeps the parent's data and drops the children's
+    data: D,
 
-    // Either objects or leaves have no items. We use Option<T> in that case
-    objects: Option<Vec<Rc<RefCell<dyn QuadObject>>>>, // Holds a maximum of MAX_OBJECTS_PER_NODE objects in each TreeNode
-    leaves: [Option<Box<TreeNode>>; 4], // Children nodes, max 4
+    // Either objects or leaves have no items. We use Option<T> for objects and an empty Vec for leaves in that case
+    objects: Option<Vec<Rc<RefCell<dyn QuadObject>>>>, // Holds a maximum of config.capacity_at_depth(depth) objects in each TreeNode
+    leaves: Vec<TreeNode<D>>, // Children nodes in row major order, fanout x fanout of them or two under SplitMode::Alternating
+    held: Vec<Rc<RefCell<dyn QuadObject>>>, // Objects straddling the leaves, only used by InsertPolicy::Enclosing
+    content_bounds: Option<TreeSurface>, // Union of the object bounds below, None while empty
 }
-impl Display for TreeNode {
+impl<D: Default + Clone> Display for TreeNode<D> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         if !self.objects.is_none() { // Check if objectvector is not None
             let objects_len = self.objects.as_ref().unwrap().len();
             write!(f, "    Objects:|{:?}", objects_len)
         } else {
-            write!(f, "    Child1:|{}\nChild2:|{}\nChild3:|{}\nChild4:|{}\n", self.leaves[0].as_ref().unwrap(), self.leaves[1].as_ref().unwrap(), self.leaves[2].as_ref().unwrap(), self.leaves[3].as_ref().unwrap())
+            write!(f, "    ")?;
+            for (index, leaf) in self.leaves.iter().enumerate() {
+                writeln!(f, "Child{}:|{}", index + 1, leaf)?;
+            }
+            Ok(())
         }
     }
 }
-impl TreeNode {
-    pub fn new(depth: i32, ox: i32, oy: i32, ix: i32, iy: i32) -> TreeNode {
+impl<D: Default + Clone> TreeNode<D> {
+    pub fn new(depth: i32, ox: i32, oy: i32, ix: i32, iy: i32) -> TreeNode<D> {
         let surface = TreeSurface { x0: ox, y0: oy, x1: ix, y1: iy };
         TreeNode {
             depth,
             surface,
+            data: D::default(),
             objects: Some(Vec::new()),
-            leaves: [None, None, None, None],
+            leaves: Vec::new(),
+            held: Vec::new(),
+            content_bounds: None,
+        }
+    }
+
+    // Whether a query can find anything here, the content bounds are tighter than the surface in sparse nodes
+    fn may_hold(&self, query_surface: &TreeSurface) -> bool {
+        query_surface.intersects(&self.surface) && self.content_bounds.is_some_and(|content| content.intersects(query_surface))
+    }
+
+    fn extend_content_bounds(&mut self, object: &Rc<RefCell<dyn QuadObject>>) {
+        let bounds = object.as_ref().borrow().bounds();
+        self.content_bounds = Some(match self.content_bounds {
+            Some(content) => content.union(&bounds),
+            None => bounds,
+        });
+    }
+
+    // After removals, rebuilt from what is stored here and the content bounds of the leaves
+    fn recompute_content_bounds(&mut self) {
+        let stored: Vec<Rc<RefCell<dyn QuadObject>>> = self.stored().to_vec();
+        self.content_bounds = self.leaves.iter().filter_map(|leaf| leaf.content_bounds).reduce(|lhs, rhs| lhs.union(&rhs));
+        for object in stored.iter() {
+            self.extend_content_bounds(object);
+        }
+    }
+
+    // Objects stored at this node itself, the leaves hold the rest
+    fn stored(&self) -> &[Rc<RefCell<dyn QuadObject>>] {
+        match &self.objects {
+            Some(objects) => objects,
+            None => &self.held,
         }
     }
 
     pub fn clear(&mut self) {
+        self.content_bounds = None;
         if self.objects.is_some() { // Check if objectvector is not None
             self.objects.as_mut().unwrap().clear();
             return;
         }
         self.leaves.iter_mut().for_each(|leaf| {
-            let leaf: &mut Box<TreeNode> = leaf.as_mut().unwrap();
             leaf.clear();
         });
-        for i in 1..3 {
-            self.leaves[i] = None;
-        }
+        self.leaves.clear();
+        self.held.clear();
+        self.objects = Some(Vec::new());
     }
 
-    pub fn insert_object(&mut self, object: Rc<RefCell<dyn QuadObject>>) {
+    pub fn insert_object(&mut self, object: Rc<RefCell<dyn QuadObject>>, config: &TreeConfig, nodes_left: &mut usize) {
+        self.extend_content_bounds(&object);
         if self.objects.is_some() { // Check if objectvector is Some
 
-            // First check if max object count has been reached
-            if self.objects.as_ref().unwrap().len() == MAX_OBJECTS_PER_NODE {
+            // First check if max object count has been reached, heavy objects count several times
+            let objects = self.objects.as_ref().unwrap();
+            let stored = objects.iter().map(|object| object.as_ref().borrow().weight());
+            if config.over_capacity(self.depth, stored, object.as_ref().borrow().weight()) {
 
-                // Check if the current size is even valid
-                let valid_to_split = (self.surface.x0 - self.surface.x1).abs() > 2 && (self.surface.y0 - self.surface.y1).abs() > 2;
-                // Check if max depth has been reached
-                let under_max_depth = self.depth < MAX_LEAF_DEPTH;
-
-                // If neither guards have been activated
-                if valid_to_split && under_max_depth {
-                    self.switch_object_to_leaves(object);
+                // Then whether size, depth and node budget still allow a split
+                if config.can_split(self.depth, &self.surface, *nodes_left) {
+                    let (columns, rows) = config.split_grid(self.depth);
+                    *nodes_left -= columns * rows;
+                    self.switch_object_to_leaves(object, config, nodes_left);
                     return;
                 }
             }
 
             // Else push object
-            self.objects.as_mut().unwrap().push(object);
+            let objects = self.objects.as_mut().unwrap();
+            if config.sorted_leaves {
+                // Sorted by the right edge rather than the center, so wide objects can not be skipped by mistake
+                let x1 = object.as_ref().borrow().bounds().x1;
+                let index = objects.partition_point(|other| other.as_ref().borrow().bounds().x1 <= x1);
+                objects.insert(index, object);
+            } else {
+                objects.push(object);
+            }
 
 
         } else { // We are using the leaves:
-            let grid_index = assign_object_to_grid(&self.surface, &object);
-
-            for value in grid_index {
-                match value {
-                    0 => {
-                        let leaf = self.leaves[0].as_mut().unwrap();
-                        leaf.insert_object(Rc::clone(&object));},
-                    1 => {
-                        let leaf = self.leaves[1].as_mut().unwrap();
-                        leaf.insert_object(Rc::clone(&object));},
-                    2 => {
-                        let leaf = self.leaves[2].as_mut().unwrap();
-                        leaf.insert_object(Rc::clone(&object));},
-                    3 => {
-                        let leaf = self.leaves[3].as_mut().unwrap();
-                        leaf.insert_object(Rc::clone(&object));},
-                    _ => (),
-                }
+            self.insert_into_leaves(object, config, nodes_left);
+        }
+    }
+
+    // Split points of the existing leaves, which keep their surfaces when grow_to_fit moves them a level deeper
+    fn leaf_bounds(&self, columns: usize) -> (Vec<i32>, Vec<i32>) {
+        let mut xs: Vec<i32> = self.leaves[..columns].iter().map(|leaf| leaf.surface.x0).collect();
+        let mut ys: Vec<i32> = self.leaves.iter().step_by(columns).map(|leaf| leaf.surface.y0).collect();
+        xs.push(self.surface.x1.saturating_add(1));
+        ys.push(self.surface.y1.saturating_add(1));
+        (xs, ys)
+    }
+
+    fn insert_into_leaves(&mut self, object: Rc<RefCell<dyn QuadObject>>, config: &TreeConfig, nodes_left: &mut usize) {
+        let (xs, ys) = self.leaf_bounds(config.split_grid(self.depth).0);
+        let grid_index = assign_object_to_bounds(&xs, &ys, &*object.as_ref().borrow());
+
+        if config.insert_policy == InsertPolicy::Enclosing {
+            // Descend only into a leaf that fits the whole object, otherwise keep it here
+            let bounds = object.as_ref().borrow().bounds();
+            match grid_index[..] {
+                [] => (),
+                [index] if self.leaves[index].surface.contains(&bounds) => self.leaves[index].insert_object(object, config, nodes_left),
+                _ => self.held.push(object),
+            }
+            return;
+        }
+        for value in grid_index {
+            self.leaves[value].insert_object(Rc::clone(&object), config, nodes_left);
+        }
+    }
+
+    pub fn remove_object(&mut self, id: u32) -> bool {
+        if let Some(objects) = self.objects.as_mut() {
+            let before = objects.len();
+            objects.retain(|object| object.as_ref().borrow().get_id() != id);
+            let removed = objects.len() != before;
+            if removed { self.recompute_content_bounds(); }
+            return removed;
+        }
+        // No short circuit, straddling objects sit in several leaves
+        let before = self.held.len();
+        self.held.retain(|object| object.as_ref().borrow().get_id() != id);
+        let mut removed = self.held.len() != before;
+        for leaf in self.leaves.iter_mut() {
+            removed |= leaf.remove_object(id);
+        }
+        if removed { self.recompute_content_bounds(); }
+        removed
+    }
+
+    fn remove_many(&mut self, ids: &HashSet<u32>) {
+        let stored = match self.objects.as_mut() {
+            Some(objects) => objects,
+            None => &mut self.held,
+        };
+        stored.retain(|object| !ids.contains(&object.as_ref().borrow().get_id()));
+        for leaf in self.leaves.iter_mut() {
+            leaf.remove_many(ids);
+        }
+        self.recompute_content_bounds();
+
+        let leaves_empty = self.leaves.iter().all(|leaf| leaf.objects.as_ref().is_some_and(|objects| objects.is_empty()));
+        if self.objects.is_none() && self.held.is_empty() && leaves_empty {
+            self.clear();
+        }
+    }
+
+    // Takes out every object overlapping the region, a node left with only empty leaves becomes a leaf again
+    fn remove_in(&mut self, region: &TreeSurface, removed: &mut Vec<Rc<RefCell<dyn QuadObject>>>) {
+        if !region.intersects(&self.surface) { return; }
+
+        let stored = match self.objects.as_mut() {
+            Some(objects) => objects,
+            None => &mut self.held,
+        };
+        stored.retain(|object| {
+            let overlapping = object.as_ref().borrow().is_overlap(region);
+            if overlapping { removed.push(Rc::clone(object)) }
+            !overlapping
+        });
+        for leaf in self.leaves.iter_mut() {
+            leaf.remove_in(region, removed);
+        }
+        self.recompute_content_bounds();
+
+        let leaves_empty = self.leaves.iter().all(|leaf| leaf.objects.as_ref().is_some_and(|objects| objects.is_empty()));
+        if self.objects.is_none() && self.held.is_empty() && leaves_empty {
+            self.clear();
+        }
+    }
+
+    fn shrink_to_fit(&mut self, config: &TreeConfig) {
+        for leaf in self.leaves.iter_mut() {
+            leaf.shrink_to_fit(config);
+        }
+
+        // A subtree that would not have split with what it holds now becomes a leaf again
+        if self.objects.is_none() {
+            let mut objects = vec![];
+            self.collect_objects(&mut objects);
+            let mut objects = dedup_by_id(objects);
+            let weight: usize = objects.iter().map(|object| object.as_ref().borrow().weight() as usize).sum();
+            if weight <= config.capacity_at_depth(self.depth) {
+                if config.sorted_leaves { objects.sort_by_key(|object| object.as_ref().borrow().bounds().x1); }
+                let content_bounds = self.content_bounds;
+                self.clear();
+                self.objects = Some(objects);
+                self.content_bounds = content_bounds;
             }
         }
+
+        if let Some(objects) = self.objects.as_mut() { objects.shrink_to_fit(); }
+        self.held.shrink_to_fit();
+        self.leaves.shrink_to_fit();
+    }
+
+    fn collect_objects(&self, objects: &mut Vec<Rc<RefCell<dyn QuadObject>>>) {
+        objects.extend(self.stored().iter().cloned());
+        self.leaves.iter().for_each(|leaf| leaf.collect_objects(objects));
+    }
+
+    fn collect_held(&self, objects: &mut Vec<Rc<RefCell<dyn QuadObject>>>) {
+        objects.extend(self.held.iter().cloned());
+        self.leaves.iter().for_each(|leaf| leaf.collect_held(objects));
+    }
+
+    // Surfaces of the nodes the object is stored in, in traversal order
+    fn leaves_holding(&self, id: u32, surfaces: &mut Vec<(i32, i32, i32, i32)>) {
+        if self.stored().iter().any(|object| object.as_ref().borrow().get_id() == id) {
+            surfaces.push((self.surface.x0, self.surface.y0, self.surface.x1, self.surface.y1));
+        }
+        for leaf in self.leaves.iter() {
+            leaf.leaves_holding(id, surfaces);
+        }
     }
 
     // Internal
-    fn switch_object_to_leaves(&mut self, extra_object: Rc<RefCell<dyn QuadObject>>) {
+    fn switch_object_to_leaves(&mut self, extra_object: Rc<RefCell<dyn QuadObject>>, config: &TreeConfig, nodes_left: &mut usize) {
         // Populating leaves
-        let (mx, my) = self.surface.mxy();
+        let (columns, rows) = config.split_grid(self.depth);
+        let (xs, ys) = self.surface.cell_bounds_at_depth(self.depth, columns, rows);
 
-        self.leaves[0] = Some(Box::new(TreeNode::new(self.depth + 1,self.surface.x0, self.surface.y0, mx - 1, my - 1)));
-        self.leaves[1] = Some(Box::new(TreeNode::new(self.depth + 1,mx, self.surface.y0, self.surface.x1, my - 1)));
-        self.leaves[2] = Some(Box::new(TreeNode::new(self.depth + 1,self.surface.x0, my, mx - 1, self.surface.y1)));
-        self.leaves[3] = Some(Box::new(TreeNode::new(self.depth + 1,mx, my, self.surface.x1, self.surface.y1)));
+        for row in 0..rows {
+            for col in 0..columns {
+                let mut leaf = TreeNode::new(self.depth + 1, xs[col], ys[row], xs[col + 1] - 1, ys[row + 1] - 1);
+                leaf.data = self.data.clone();
+                self.leaves.push(leaf);
+            }
+        }
+        debug_assert!(self.leaves.iter().all(|leaf| !leaf.surface.is_degenerate()), "split of {} made an inverted child", self.surface);
 
         // Add extra object
-        self.objects.as_mut().unwrap().push(extra_object);
+        let mut objects = self.objects.take().unwrap();
+        objects.push(extra_object);
 
         // Loop through all object (including the extra)
-        for object in self.objects.as_ref().unwrap() {
-            let grid_index = assign_object_to_grid(&self.surface, &object);
-
-            let mut index = 0;
-            self.leaves.iter_mut().for_each(|leaf| {
-                let leaf: &mut Box<TreeNode> = leaf.as_mut().unwrap();
-
-                if grid_index.iter().any(|&x| (x as usize) == index) {
-                    leaf.insert_object(Rc::clone(&object));
-                }
-                index += 1;
-            });
+        for object in objects {
+            self.insert_into_leaves(object, config, nodes_left);
         }
-        self.objects = None;
     }
 }
 
 // ----------------------------------------
 // Stats Functions
 // ----------------------------------------
-impl QuadTree {
+// A copy of what a node looks like, without its objects or children
+#[derive(Clone, Copy)]
+pub struct NodeInfo {
+    pub depth: i32,
+    pub surface: TreeSurface,
+    pub object_count: usize, // Objects stored at the node itself
+    pub is_leaf: bool,
+}
+
+impl<D: Default + Clone> QuadTree<D> {
     pub fn node_count(&self) -> i32 { self.top_node.node_count() }
     pub fn deepest_node(&self) -> i32 { self.top_node.deepest_node() }
     pub fn object_count(&self) -> i32 { self.top_node.object_count() }
     pub fn empty_node_count(&self) -> i32 { self.top_node.empty_node_count() }
-}
 
-impl TreeNode {
-    pub fn contains_object(&self, to_check_object: &Rc<RefCell<dyn QuadObject>>) -> bool {
-        if self.objects.is_some() {
-            return self.objects.as_ref().unwrap().iter().any(
-                |object| {
-                    object.as_ref().borrow().get_id() == to_check_object.as_ref().borrow().get_id()
-                }
+    // Whether the next split would go over max_nodes, so full nodes keep growing instead
+    pub fn at_node_limit(&self) -> bool {
+        self.config.max_nodes.is_some() && self.nodes_left() < self.config.split_children()
+    }
+
+    // Nodes in use over the nodes the stored objects need, about 1.0 right after building
+    // A split spreads capacity + 1 objects over its children, so leaves of a fresh build are about half full. The need is
+    // the smallest tree with enough such leaves for every stored copy, each split turning one leaf into split_children.
+    // Removals and moves leave split nodes behind, so the ratio grows until the next rebuild
+    pub fn fragmentation(&self) -> f32 {
+        let (children, half_full) = (self.config.split_children(), self.config.capacity.div_ceil(2).max(1));
+        let leaves = (self.object_count() as usize).div_ceil(half_full).max(1);
+        let splits = (leaves - 1).div_ceil(children - 1);
+        self.node_count() as f32 / (1 + splits * children) as f32
+    }
+
+    // Most nodes any single object is stored in, high values mean heavy straddling inflates object_count
+    pub fn max_object_fanout(&self) -> usize {
+        let mut objects = vec![];
+        self.top_node.collect_objects(&mut objects);
+        let mut copies: HashMap<u32, usize> = HashMap::new();
+        for object in objects.iter() {
+            *copies.entry(object.as_ref().borrow().get_id()).or_insert(0) += 1;
+        }
+        copies.values().copied().max().unwrap_or(0)
+    }
+
+    // A capacity that balances descending the tree against scanning a leaf, for the objects currently stored
+    // Reaching a leaf at depth d checks about d * fanout^2 child surfaces, so a leaf holding that many objects costs the
+    // same to scan as to reach. The mean depth of the non empty leaves stands in for d. Leaves stuck at max depth above
+    // capacity are clusters no split separates, a capacity below their size only adds nodes, so it never goes under them.
+    pub fn suggest_capacity(&self) -> usize {
+        let leaves: Vec<NodeInfo> = self.nodes_breadth_first().into_iter().filter(|node| node.is_leaf && node.object_count > 0).collect();
+        if leaves.is_empty() { return self.config.capacity; }
+
+        let mean_depth = leaves.iter().map(|leaf| leaf.depth as f32).sum::<f32>() / leaves.len() as f32;
+        let balanced = (mean_depth * self.config.split_children() as f32).round() as usize;
+        let stuck = leaves.iter().filter(|leaf| leaf.depth >= self.config.max_depth).map(|leaf| leaf.object_count).max().unwrap_or(0);
+        balanced.max(stuck).max(1)
+    }
+
+    // Every object once, by ascending id, the same list whatever shape the tree has
+    pub fn objects_sorted_by_id(&self) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut objects = self.unique_objects();
+        objects.sort_by_key(|object| object.as_ref().borrow().get_id());
+        objects
+    }
+
+    // Objects stored at the root itself without descending, a split root only keeps straddlers under InsertPolicy::Enclosing
+    pub fn root_objects(&self) -> Vec<Rc<RefCell<dyn QuadObject>>> { self.top_node.stored().to_vec() }
+
+    // object_count of each root child, [top left, top right, bottom left, bottom right], for a coarse 2x2 minimap
+    // A root that is still a leaf or splits in another shape has its objects bucketed by center instead
+    pub fn root_quadrant_object_counts(&self) -> [i32; 4] {
+        if let [top_left, top_right, bottom_left, bottom_right] = &self.top_node.leaves[..] {
+            return [top_left.object_count(), top_right.object_count(), bottom_left.object_count(), bottom_right.object_count()];
+        }
+        let (mx, my) = self.config.surface.mxy_at_depth(self.top_node.depth);
+        let mut counts = [0; 4];
+        for object in self.unique_objects().iter() {
+            let (cx, cy) = object.as_ref().borrow().center();
+            counts[(cy >= my) as usize * 2 + (cx >= mx) as usize] += 1;
+        }
+        counts
+    }
+
+    // Every node level by level, each level in row major order of the splits
+    pub fn nodes_breadth_first(&self) -> Vec<NodeInfo> {
+        let mut nodes = vec![];
+        let mut queue: VecDeque<&TreeNode<D>> = VecDeque::from([self.top_node.as_ref()]);
+        while let Some(node) = queue.pop_front() {
+            nodes.push(node.info());
+            queue.extend(node.leaves.iter());
+        }
+        nodes
+    }
+
+    // Node surfaces as (x0, y0, x1, y1) normalized to the tree surface, in the order of nodes_breadth_first
+    pub fn normalized_node_surfaces(&self) -> Vec<(f32, f32, f32, f32)> {
+        self.nodes_breadth_first().iter().map(|node| {
+            let (x0, y0) = self.config.surface.normalize_point(node.surface.x0, node.surface.y0);
+            let (x1, y1) = self.config.surface.normalize_point(node.surface.x1, node.surface.y1);
+            (x0, y0, x1, y1)
+        }).collect()
+    }
+
+    // Every node with its user data, parents before their children
+    pub fn for_each_node<F: FnMut(&NodeInfo, &D)>(&self, mut visit: F) {
+        self.top_node.for_each_node(&mut visit);
+    }
+    pub fn for_each_node_mut<F: FnMut(&NodeInfo, &mut D)>(&mut self, mut visit: F) {
+        self.top_node.for_each_node_mut(&mut visit);
+    }
+
+    // User data of the deepest node containing the point, None outside the tree surface
+    pub fn data_at(&self, x: i32, y: i32) -> Option<&D> {
+        let point = TreeSurface { x0: x, y0: y, x1: x, y1: y };
+        if !self.config.surface.contains(&point) { return None; }
+        Some(&self.top_node.enclosing_node(&point).data)
+    }
+    pub fn data_at_mut(&mut self, x: i32, y: i32) -> Option<&mut D> {
+        let point = TreeSurface { x0: x, y0: y, x1: x, y1: y };
+        if !self.config.surface.contains(&point) { return None; }
+        Some(&mut self.top_node.enclosing_node_mut(&point).data)
+    }
+
+    // Child indices from the root to every node storing the object, straddlers have one path per copy
+    pub fn path_to(&self, object: &Rc<RefCell<dyn QuadObject>>) -> Vec<Vec<u8>> {
+        let mut paths = vec![];
+        self.top_node.paths_to(object.as_ref().borrow().get_id(), &mut vec![], &mut paths);
+        paths
+    }
+
+    // Same node surfaces, same splits and the same object ids in every node, in any order within a node
+    pub fn structurally_eq(&self, other: &QuadTree<D>) -> bool {
+        self.top_node.structurally_eq(&other.top_node)
+    }
+
+    // Surfaces of nodes with x1 < x0 or y1 < y0, a split that went wrong at small sizes
+    pub fn find_degenerate_nodes(&self) -> Vec<TreeSurface> {
+        let mut surfaces = vec![];
+        self.top_node.find_degenerate(&mut surfaces);
+        surfaces
+    }
+}
+
+impl<D: Default + Clone> TreeNode<D> {
+    pub fn contains_object(&self, to_check_object: &Rc<RefCell<dyn QuadObject>>) -> bool {
+        if self.objects.is_some() {
+            return self.objects.as_ref().unwrap().iter().any(
+                |object| {
+                    object.as_ref().borrow().get_id() == to_check_object.as_ref().borrow().get_id()
+                }
             )
         }
-        self.leaves.iter().any(|leaf| {
-            let contains_object = leaf.as_ref().unwrap().contains_object(to_check_object);
-            contains_object
-        })
+        let id = to_check_object.as_ref().borrow().get_id();
+        self.held.iter().any(|object| object.as_ref().borrow().get_id() == id) ||
+            self.leaves.iter().any(|leaf| leaf.contains_object(to_check_object))
     }
 
     pub fn node_count(&self) -> i32 {
@@ -250,21 +1181,24 @@ impl TreeNode {
         } else {
             let sum =
             self.leaves.iter().map(|leaf| {
-                let node_count = leaf.as_ref().unwrap().node_count();
+                let node_count = leaf.node_count();
                 return node_count
             }).sum::<i32>();
             sum + 1
         }
     }
 
+    // One level deeper for the whole subtree, surfaces stay as they are
+    fn deepen(&mut self) {
+        self.depth += 1;
+        self.leaves.iter_mut().for_each(|leaf| leaf.deepen());
+    }
+
     pub fn deepest_node(&self) -> i32 {
         if !self.objects.is_none() { // Check if objectvector is not None
             self.depth
         } else {
-            self.leaves.iter().map(|leaf| {
-                let node_count = leaf.as_ref().unwrap().deepest_node();
-                node_count
-            }).max().unwrap()
+            self.leaves.iter().map(|leaf| leaf.deepest_node()).max().unwrap()
         }
     }
 
@@ -272,10 +1206,7 @@ impl TreeNode {
         if !self.objects.is_none() { // Check if objectvector is not None
             self.objects.as_ref().unwrap().len() as i32
         } else {
-            self.leaves.iter().map(|leaf| {
-                let node_count = leaf.as_ref().unwrap().object_count();
-                node_count
-            }).sum()
+            self.leaves.iter().map(|leaf| leaf.object_count()).sum::<i32>() + self.held.len() as i32
         }
     }
 
@@ -283,10 +1214,7 @@ impl TreeNode {
         if self.objects.is_some() { // Check if objectvector is not None
             0
         } else {
-            self.leaves.iter().map(|leaf| {
-                let node_count = leaf.as_ref().unwrap().leaf_count();
-                node_count
-            }).sum::<i32>() + 4
+            self.leaves.iter().map(|leaf| leaf.leaf_count()).sum::<i32>() + self.leaves.len() as i32
         }
     }
 
@@ -294,13 +1222,46 @@ impl TreeNode {
         if self.objects.is_some() { // Check if objectvector is not None
             self.object_count()
         } else {
-            self.leaves.iter().map(|leaf| {
-                let max_object_count = leaf.as_ref().unwrap().max_objects();
-                max_object_count
-            }).max().unwrap()
+            self.leaves.iter().map(|leaf| leaf.max_objects()).max().unwrap()
         }
     }
 
+    fn info(&self) -> NodeInfo {
+        NodeInfo { depth: self.depth, surface: self.surface, object_count: self.stored().len(), is_leaf: self.objects.is_some() }
+    }
+
+    fn for_each_node<F: FnMut(&NodeInfo, &D)>(&self, visit: &mut F) {
+        visit(&self.info(), &self.data);
+        self.leaves.iter().for_each(|leaf| leaf.for_each_node(visit));
+    }
+    fn for_each_node_mut<F: FnMut(&NodeInfo, &mut D)>(&mut self, visit: &mut F) {
+        let info = self.info();
+        visit(&info, &mut self.data);
+        self.leaves.iter_mut().for_each(|leaf| leaf.for_each_node_mut(visit));
+    }
+
+    fn structurally_eq(&self, other: &TreeNode<D>) -> bool {
+        let ids = |node: &TreeNode<D>| node.stored().iter().map(|object| object.as_ref().borrow().get_id()).collect::<HashSet<u32>>();
+        self.surface == other.surface && self.objects.is_some() == other.objects.is_some() && ids(self) == ids(other) &&
+            self.leaves.len() == other.leaves.len() && self.leaves.iter().zip(other.leaves.iter()).all(|(lhs, rhs)| lhs.structurally_eq(rhs))
+    }
+
+    fn paths_to(&self, id: u32, path: &mut Vec<u8>, paths: &mut Vec<Vec<u8>>) {
+        if self.stored().iter().any(|object| object.as_ref().borrow().get_id() == id) {
+            paths.push(path.clone());
+        }
+        for (index, leaf) in self.leaves.iter().enumerate() {
+            path.push(index as u8);
+            leaf.paths_to(id, path, paths);
+            path.pop();
+        }
+    }
+
+    fn find_degenerate(&self, surfaces: &mut Vec<TreeSurface>) {
+        if self.surface.is_degenerate() { surfaces.push(self.surface); }
+        self.leaves.iter().for_each(|leaf| leaf.find_degenerate(surfaces));
+    }
+
     pub fn empty_node_count(&self) -> i32 {
         if self.objects.is_some() { // Check if objectvector is not None
             if self.objects.as_ref().unwrap().is_empty() {
@@ -309,10 +1270,7 @@ impl TreeNode {
                 0
             }
         } else {
-            self.leaves.iter().map(|leaf| {
-                let max_object_count = leaf.as_ref().unwrap().empty_node_count();
-                max_object_count
-            }).sum::<i32>()
+            self.leaves.iter().map(|leaf| leaf.empty_node_count()).sum::<i32>()
         }
     }
 }
@@ -320,135 +1278,2488 @@ impl TreeNode {
 // ----------------------------------------
 // Complex methods
 // ----------------------------------------
-impl QuadTree {
-    pub fn query_surface(&self, query_surface: &Rectangle) -> Vec<Rc<RefCell<dyn QuadObject>>> {
-        self.top_node.query_by_surface(query_surface)
+// Radius neighbours per (id, radius) from earlier queries, dropped as soon as the tree changes
+#[derive(Default)]
+pub struct NeighbourCache {
+    generation: u64,
+    neighbours: HashMap<(u32, i32), Neighbours>,
+    hits: usize,
+    misses: usize,
+}
+
+type Neighbours = Vec<Rc<RefCell<dyn QuadObject>>>;
+// (contained, partial) from classify_in
+type Classified = (Vec<Rc<RefCell<dyn QuadObject>>>, Vec<Rc<RefCell<dyn QuadObject>>>);
+// Two overlapping objects, the lower id first
+type CollisionPair = (Rc<RefCell<dyn QuadObject>>, Rc<RefCell<dyn QuadObject>>);
+
+impl NeighbourCache {
+    pub fn new() -> NeighbourCache {
+        NeighbourCache::default()
+    }
+    pub fn hits(&self) -> usize {
+        self.hits
     }
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+}
 
-    pub fn query_neighbours_and_condition(&self, query_object: &Rc<RefCell<dyn QuadObject>>, k: Option<i32>) -> Vec<Rc<RefCell<dyn QuadObject>>> {
-        self.top_node.query_by_object(query_object, k)
+// Running totals over every query method, including the queries built on top of them
+// Cells, so queries through &self can count and callers can hold a reference while querying
+#[derive(Clone, Default, Debug)]
+pub struct QueryStats {
+    queries: Cell<usize>,
+    results: Cell<usize>, // Objects returned, unique except for query_surface which returns every stored copy
+    nodes_visited: Cell<usize>, // Nodes the descents looked into, after pruning
+}
+
+impl QueryStats {
+    pub fn queries(&self) -> usize {
+        self.queries.get()
+    }
+    pub fn results(&self) -> usize {
+        self.results.get()
+    }
+    pub fn nodes_visited(&self) -> usize {
+        self.nodes_visited.get()
+    }
+    pub fn mean_results(&self) -> f32 {
+        if self.queries() == 0 { 0.0 } else { self.results() as f32 / self.queries() as f32 }
+    }
+    pub fn mean_nodes_visited(&self) -> f32 {
+        if self.queries() == 0 { 0.0 } else { self.nodes_visited() as f32 / self.queries() as f32 }
+    }
+    fn record(&self, results: usize, nodes_visited: usize) {
+        self.queries.set(self.queries.get() + 1);
+        self.results.set(self.results.get() + results);
+        self.nodes_visited.set(self.nodes_visited.get() + nodes_visited);
     }
 }
-impl TreeNode {
-    pub fn query_by_surface(&self, query_surface: &Rectangle) -> Vec<Rc<RefCell<dyn QuadObject>>> {
-        let mut query_result = vec![];
 
-        if self.objects.is_some() { // Check if objectvector is not None
-            for object in self.objects.as_ref().unwrap().iter() {
-                if query_surface.is_rect_overlap(object.borrow()) { query_result.push(Rc::clone(object)) }
+// Lazy query_objects_in, a stack of nodes still to visit and the objects of the node being scanned
+// Counted as one query when created, results and nodes are added to the stats as they are pulled
+struct QueryIter<'a, D: Default + Clone> {
+    query_surface: TreeSurface,
+    stack: Vec<&'a TreeNode<D>>,
+    current: std::slice::Iter<'a, Rc<RefCell<dyn QuadObject>>>,
+    seen: HashSet<u32>,
+    stats: &'a QueryStats,
+}
+
+impl<D: Default + Clone> Iterator for QueryIter<'_, D> {
+    type Item = Rc<RefCell<dyn QuadObject>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            for object in self.current.by_ref() {
+                let (id, overlaps) = { let object = object.as_ref().borrow(); (object.get_id(), object.is_overlap(&self.query_surface)) };
+                if overlaps && self.seen.insert(id) {
+                    self.stats.results.set(self.stats.results.get() + 1);
+                    return Some(Rc::clone(object));
+                }
             }
-        } else {
-            self.leaves.iter().map(|leaf| {
-                query_result.append(leaf.as_ref().unwrap().query_by_surface(&query_surface).as_mut());
-            }).collect()
+            let node = self.stack.pop()?;
+            if !node.may_hold(&self.query_surface) { continue; }
+            self.stats.nodes_visited.set(self.stats.nodes_visited.get() + 1);
+            self.current = node.stored().iter();
+            // Reversed so the first leaf is visited first, the same order as query_objects_in
+            self.stack.extend(node.leaves.iter().rev());
         }
+    }
+}
+
+impl<D: Default + Clone> QuadTree<D> {
+    // Totals since the tree was built or reset_query_stats
+    pub fn query_stats(&self) -> &QueryStats {
+        &self.query_stats
+    }
+    pub fn reset_query_stats(&mut self) {
+        self.query_stats = QueryStats::default();
+    }
+    fn record_query(&self, results: usize, nodes_visited: usize) {
+        self.query_stats.record(results, nodes_visited);
+    }
+
+    pub fn query_surface(&self, query_surface: &Rectangle) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut query_result = vec![];
+        let nodes_visited = self.top_node.query_by_surface(query_surface, &mut query_result);
+        self.record_query(query_result.len(), nodes_visited);
         query_result
     }
 
-    pub fn query_by_object(&self, query_object: &Rc<RefCell<dyn QuadObject>>, k: Option<i32>) -> Vec<Rc<RefCell<dyn QuadObject>>> {
-        if self.objects.is_some() {
-            // If there are objects in vector then we return the vector
-            // But, we cannot return the query object
-            // So, we need to check if object is self
-            let all_objects = self.objects.as_ref().unwrap().clone();
-            return all_objects.into_iter().filter(
-                |object| {
-                    let lhs = query_object.as_ref().borrow();
-                    let rhs = object.as_ref().borrow();
-                    return lhs.get_id() != rhs.get_id()
+    // Like query_surface, but skips leaves outside the query and returns each object once
+    pub fn query_objects_in(&self, query: &Rectangle) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        self.query_objects_in_with_capacity(query, 0)
+    }
+
+    // query_objects_in with the result allocated for capacity_hint objects up front
+    pub fn query_objects_in_with_capacity(&self, query: &Rectangle, capacity_hint: usize) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut query_result = Vec::with_capacity(capacity_hint);
+        let nodes_visited = if self.config.sorted_leaves {
+            self.top_node.query_overlapping_sorted(&query.to_tree_surface(), &mut query_result)
+        } else {
+            self.top_node.query_overlapping(&query.to_tree_surface(), &mut query_result)
+        };
+        // In place, so the result keeps its allocation
+        let mut seen = HashSet::new();
+        query_result.retain(|object| seen.insert(object.as_ref().borrow().get_id()));
+        self.record_query(query_result.len(), nodes_visited);
+        query_result
+    }
+
+    // query_objects_in as an iterator, nodes are only visited as items are pulled so .take(n) stops early
+    pub fn query_iter<'a>(&'a self, query: &Rectangle) -> impl Iterator<Item = Rc<RefCell<dyn QuadObject>>> + 'a {
+        self.record_query(0, 0);
+        QueryIter { query_surface: query.to_tree_surface(), stack: vec![self.top_node.as_ref()], current: [].iter(), seen: HashSet::new(), stats: &self.query_stats }
+    }
+
+    // Objects of one kind overlapping the query, e.g. query_kind_in(query, ObjectKind::Circle) for circles
+    pub fn query_kind_in(&self, query: &Rectangle, kind: ObjectKind) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        self.query_objects_in(query).into_iter().filter(|object| object.as_ref().borrow().kind() == kind).collect()
+    }
+
+    // Boids overlapping the query, what flocking usually wants
+    pub fn query_boids_in(&self, query: &Rectangle) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        self.query_kind_in(query, ObjectKind::Boid)
+    }
+
+    // Boids in the region heading at most max_angle radians away from the target, other kinds are left out
+    pub fn query_facing(&self, target: (i32, i32), region: &Rectangle, max_angle: f32) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        self.query_objects_in(region).into_iter().filter(|object| {
+            object.as_ref().borrow().get_boid().is_some_and(|boid| boid.faces(target.0 as f32, target.1 as f32, max_angle))
+        }).collect()
+    }
+
+    // Objects whose bounds, swept along their velocity over dt updates, reach the query, so fast movers are found early
+    // Nothing moves further than max_speed * dt, so only the query grown by that much is searched
+    // Velocities changed without update_object are not seen by max_speed, reinsert objects after speeding them up
+    pub fn query_swept(&self, query: &Rectangle, dt: f32) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let query_surface = query.to_tree_surface();
+        let reach = (self.max_speed * dt).abs().ceil() as i32;
+        let search = TreeSurface { x0: query_surface.x0.saturating_sub(reach), y0: query_surface.y0.saturating_sub(reach), x1: query_surface.x1.saturating_add(reach), y1: query_surface.y1.saturating_add(reach) };
+        let mut candidates = vec![];
+        let nodes_visited = self.top_node.query_overlapping(&search, &mut candidates);
+        let query_result: Vec<Rc<RefCell<dyn QuadObject>>> = dedup_by_id(candidates).into_iter().filter(|object| {
+            let object = object.as_ref().borrow();
+            let (bounds, (vx, vy)) = (object.bounds(), object.velocity_vec());
+            let (dx, dy) = ((vx * dt).round() as i32, (vy * dt).round() as i32);
+            let moved = TreeSurface { x0: bounds.x0.saturating_add(dx), y0: bounds.y0.saturating_add(dy), x1: bounds.x1.saturating_add(dx), y1: bounds.y1.saturating_add(dy) };
+            bounds.union(&moved).intersects(&query_surface)
+        }).collect();
+        self.record_query(query_result.len(), nodes_visited);
+        query_result
+    }
+
+    // Objects to draw for this viewport, anything off screen is culled
+    pub fn visible_objects(&self, viewport: &Rectangle) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        self.query_objects_in(viewport)
+    }
+
+    // Matches sorted by ascending id, which is insertion order when ids are handed out incrementally
+    pub fn query_objects_in_insertion_order(&self, query: &Rectangle) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut query_result = self.query_objects_in(query);
+        query_result.sort_by_key(|object| object.as_ref().borrow().get_id());
+        query_result
+    }
+
+    // Matches in raster order, sorted by the y and then the x of their center
+    pub fn query_scanline_order(&self, query: &Rectangle) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut query_result = self.query_objects_in(query);
+        query_result.sort_by_key(|object| {
+            let (cx, cy) = object.as_ref().borrow().center();
+            (cy, cx)
+        });
+        query_result
+    }
+
+    // Matches sorted by bounding box area, largest first, equal areas by ascending id
+    pub fn query_in_by_area(&self, query: &Rectangle) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut query_result = self.query_objects_in(query);
+        query_result.sort_by_key(|object| {
+            let object = object.as_ref().borrow();
+            (std::cmp::Reverse(object.bounds().area()), object.get_id())
+        });
+        query_result
+    }
+
+    // Surfaces of the leaves a query touches, without looking at their objects
+    pub fn leaves_in(&self, query: &Rectangle) -> Vec<TreeSurface> {
+        let mut surfaces = vec![];
+        let nodes_visited = self.top_node.leaves_in(&query.to_tree_surface(), &mut surfaces);
+        self.record_query(surfaces.len(), nodes_visited);
+        surfaces
+    }
+
+    // Surface and depth of the deepest node containing all of rect, the root when rect straddles its first split
+    pub fn enclosing_node(&self, rect: &Rectangle) -> (TreeSurface, i32) {
+        let node = self.top_node.enclosing_node(&rect.to_tree_surface());
+        (node.surface, node.depth)
+    }
+
+    // Approximate number of objects a query returns, see estimate_count_in_to_depth
+    pub fn estimate_count_in(&self, query: &Rectangle) -> usize {
+        self.estimate_count_in_to_depth(query, 2)
+    }
+
+    // Approximation only: descends at most `levels` below the root, then scales each touched subtree's
+    // object count by the fraction of its area the query covers, assuming objects spread evenly
+    // Straddling objects are counted once per leaf they sit in
+    pub fn estimate_count_in_to_depth(&self, query: &Rectangle, levels: i32) -> usize {
+        self.top_node.estimate_count(&query.to_tree_surface(), self.top_node.depth + levels).round() as usize
+    }
+
+    // Calls `on_pair` once for every (object in self, object in other) that overlap
+    // Recorded as one query of other, the tree it searches
+    pub fn join<E: Default + Clone, F: FnMut(&Rc<RefCell<dyn QuadObject>>, &Rc<RefCell<dyn QuadObject>>)>(&self, other: &QuadTree<E>, mut on_pair: F) {
+        let mut pairs = 0;
+        let nodes_visited = self.join_counting(other, |object, candidate| {
+            pairs += 1;
+            on_pair(object, candidate);
+        });
+        other.record_query(pairs, nodes_visited);
+    }
+
+    // join without recording it, returns how many nodes of other it looked into
+    fn join_counting<E: Default + Clone, F: FnMut(&Rc<RefCell<dyn QuadObject>>, &Rc<RefCell<dyn QuadObject>>)>(&self, other: &QuadTree<E>, mut on_pair: F) -> usize {
+        let mut nodes_visited = 0;
+        for object in self.unique_objects() {
+            let bounds = object.as_ref().borrow().bounds();
+            let mut candidates = vec![];
+            nodes_visited += other.top_node.query_overlapping(&bounds, &mut candidates);
+
+            for candidate in dedup_by_id(candidates) {
+                if overlaps(&*object.as_ref().borrow(), &*candidate.as_ref().borrow()) {
+                    on_pair(&object, &candidate);
                 }
-            ).clone().collect()
+            }
         }
+        nodes_visited
+    }
 
-        // Else, the real shit begins
-        let mut query_result = vec![];
+    // Number of other objects each object overlaps, by id, zero for isolated objects
+    pub fn overlap_counts(&self) -> HashMap<u32, usize> {
+        let mut counts: HashMap<u32, usize> = self.unique_objects().iter().map(|object| (object.as_ref().borrow().get_id(), 0)).collect();
+        self.join(self, |object, other| {
+            let id = object.as_ref().borrow().get_id();
+            if id != other.as_ref().borrow().get_id() {
+                *counts.entry(id).or_insert(0) += 1;
+            }
+        });
+        counts
+    }
 
-        // Loop through leaves, if leaf contains the object then query as well
-        for node_index in 0..4 {
-            let node = self.leaves[node_index].as_ref().unwrap();
-            if node.contains_object(query_object) {
-                query_result.append(&mut node.query_by_object(query_object, k))
+    // Every pair of overlapping objects once, the lower id first
+    // Sorted by (lower id, higher id), so the order depends only on the ids and never on the shape of the tree
+    pub fn collision_pairs(&self) -> Vec<CollisionPair> {
+        let mut pairs = vec![];
+        let nodes_visited = self.join_counting(self, |object, other| {
+            if object.as_ref().borrow().get_id() < other.as_ref().borrow().get_id() {
+                pairs.push((Rc::clone(object), Rc::clone(other)));
             }
+        });
+        pairs.sort_by_key(|(object, other)| (object.as_ref().borrow().get_id(), other.as_ref().borrow().get_id()));
+        self.record_query(pairs.len(), nodes_visited);
+        pairs
+    }
+
+    // Calls on_collide on both objects of every collision pair, in the order of collision_pairs
+    // The query is recorded by collision_pairs
+    pub fn resolve_collisions(&self) {
+        for (object, other) in self.collision_pairs() {
+            object.as_ref().borrow_mut().on_collide(&*other.as_ref().borrow());
+            other.as_ref().borrow_mut().on_collide(&*object.as_ref().borrow());
         }
-        // Resulting vector should give all nodes which are in the same nodes as the query_object
-        // Further filtering now
-        return query_result
     }
-}
 
-// ----------------------------------------
-// Draw Functions
-// ----------------------------------------
-impl QuadTree {
-    pub fn draw(&self) {
-        // Borders
-        draw_line(self.surface.x0 as f32, self.surface.y0 as f32, self.surface.x1 as f32, self.surface.y0 as f32, LINE_WIDTH, DARKGRAY);
-        draw_line(self.surface.x0 as f32, self.surface.y1 as f32, self.surface.x1 as f32, self.surface.y1 as f32, LINE_WIDTH, DARKGRAY);
-        draw_line(self.surface.x0 as f32, self.surface.y0 as f32, self.surface.x0 as f32, self.surface.y1 as f32, LINE_WIDTH, DARKGRAY);
-        draw_line(self.surface.x1 as f32, self.surface.y0 as f32, self.surface.x1 as f32, self.surface.y1 as f32, LINE_WIDTH, DARKGRAY);
+    // Everything stored in the leaves around the object, so flocking does not stop at leaf borders
+    pub fn query_neighbours_with_adjacency(&self, query_object: &Rc<RefCell<dyn QuadObject>>, adjacency: Adjacency) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let id = query_object.as_ref().borrow().get_id();
+        let mut holding = vec![];
+        self.top_node.leaves_holding(id, &mut holding);
 
-        // Children
-        self.top_node.draw();
+        let (mut query_result, mut nodes_visited) = (vec![], 0);
+        for (x0, y0, x1, y1) in holding {
+            // One pixel beyond a leaf lies in the leaves next to it, the two crosses leave out the corners
+            // Saturating, a leaf on the edge of the i32 range has no neighbours past it
+            let (outer_x0, outer_y0, outer_x1, outer_y1) = (x0.saturating_sub(1), y0.saturating_sub(1), x1.saturating_add(1), y1.saturating_add(1));
+            let regions = match adjacency {
+                Adjacency::SameLeaf => vec![TreeSurface { x0, y0, x1, y1 }],
+                Adjacency::FourWay => vec![TreeSurface { x0: outer_x0, y0, x1: outer_x1, y1 }, TreeSurface { x0, y0: outer_y0, x1, y1: outer_y1 }],
+                Adjacency::EightWay => vec![TreeSurface { x0: outer_x0, y0: outer_y0, x1: outer_x1, y1: outer_y1 }],
+            };
+            for region in regions.iter() {
+                nodes_visited += self.top_node.collect_touching(region, &mut query_result);
+            }
+        }
+        let query_result: Vec<Rc<RefCell<dyn QuadObject>>> = dedup_by_id(query_result).into_iter().filter(|object| object.as_ref().borrow().get_id() != id).collect();
+        self.record_query(query_result.len(), nodes_visited);
+        query_result
+    }
 
-        // Text
-        let draw_x = self.surface.x1 as f32 + 5.0;
-        let node_count = self.node_count();
-        let empty_nodes = self.empty_node_count();
+    // Groups of objects chained together by centers at most link_distance apart, union find over radius queries
+    pub fn clusters(&self, link_distance: i32) -> Vec<Vec<Rc<RefCell<dyn QuadObject>>>> {
+        let objects = self.unique_objects();
+        let index_by_id: HashMap<u32, usize> = objects.iter().enumerate().map(|(index, object)| (object.as_ref().borrow().get_id(), index)).collect();
 
-        let mut info_str = String::from("Node count:");
-        info_str.push_str(&node_count.to_string());
-        draw_text(info_str.as_str(), draw_x, 40.0, 15.0, WHITE);
-        info_str.clear();
+        fn root(parents: &mut [usize], mut index: usize) -> usize {
+            while parents[index] != index {
+                parents[index] = parents[parents[index]];
+                index = parents[index];
+            }
+            index
+        }
+        let mut parents: Vec<usize> = (0..objects.len()).collect();
+        for (index, object) in objects.iter().enumerate() {
+            let (cx, cy) = object.as_ref().borrow().center();
+            for neighbour in self.query_in_radius(cx, cy, link_distance) {
+                let (a, b) = (root(&mut parents, index), root(&mut parents, index_by_id[&neighbour.as_ref().borrow().get_id()]));
+                parents[a.max(b)] = a.min(b);
+            }
+        }
 
-        info_str.push_str("Empty nodes:");
-        info_str.push_str(&empty_nodes.to_string());
-        info_str.push_str(" / ");
-        info_str.push_str(&node_count.to_string());
-        info_str.push_str(" -> ");
-        info_str.push_str(&(100.0 * empty_nodes as f32 / node_count as f32).to_string());
-        info_str.push_str("%");
-        draw_text(info_str.as_str(), draw_x, 60.0, 15.0, WHITE);
-        info_str.clear();
+        // Clusters in order of their first object
+        let mut cluster_by_root: HashMap<usize, usize> = HashMap::new();
+        let mut clusters: Vec<Vec<Rc<RefCell<dyn QuadObject>>>> = vec![];
+        for (index, object) in objects.iter().enumerate() {
+            let cluster = *cluster_by_root.entry(root(&mut parents, index)).or_insert_with(|| {
+                clusters.push(vec![]);
+                clusters.len() - 1
+            });
+            clusters[cluster].push(Rc::clone(object));
+        }
+        clusters
+    }
 
-        info_str.push_str("Deepest node:");
-        info_str.push_str(&self.deepest_node().to_string());
-        draw_text(info_str.as_str(), draw_x, 80.0, 15.0, WHITE);
-        info_str.clear();
+    // Whether a chain of objects with centers at most link_distance apart links the two ids, false if either is missing
+    pub fn connected(&self, a_id: u32, b_id: u32, link_distance: i32) -> bool {
+        self.clusters(link_distance).iter().any(|cluster| {
+            let has = |id: u32| cluster.iter().any(|object| object.as_ref().borrow().get_id() == id);
+            has(a_id) && has(b_id)
+        })
+    }
 
-        info_str.push_str("Objects in nodes:");
-        info_str.push_str(&self.object_count().to_string());
-        draw_text(info_str.as_str(), draw_x, 100.0, 15.0, WHITE);
-        info_str.clear();
+    pub fn query_neighbours_and_condition(&self, query_object: &Rc<RefCell<dyn QuadObject>>, k: Option<i32>) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut query_result = vec![];
+        let nodes_visited = self.top_node.query_by_object(query_object, k, &mut query_result);
+        self.record_query(query_result.len(), nodes_visited);
+        query_result
+    }
 
-        info_str.push_str("Allocated nodes:");
-        info_str.push_str(&self.top_node.leaf_count().to_string());
-        draw_text(info_str.as_str(), draw_x, 120.0, 15.0, WHITE);
-        info_str.clear();
+    // Objects overlapping the query with the depth of the leaf they were found in
+    // Straddling objects are reported once, with the shallowest leaf depth
+    pub fn query_objects_in_with_depth(&self, query: &Rectangle) -> Vec<(Rc<RefCell<dyn QuadObject>>, i32)> {
+        let mut found = vec![];
+        let nodes_visited = self.top_node.query_by_surface_with_depth(query, &mut found);
 
-        info_str.push_str("Max objects in nodes:");
-        info_str.push_str(&self.top_node.max_objects().to_string());
-        draw_text(info_str.as_str(), draw_x, 140.0, 15.0, WHITE);
-        info_str.clear();
+        let mut query_result: Vec<(Rc<RefCell<dyn QuadObject>>, i32)> = vec![];
+        let mut index_by_id: HashMap<u32, usize> = HashMap::new();
+        for (object, depth) in found {
+            let id = object.as_ref().borrow().get_id();
+            match index_by_id.get(&id) {
+                Some(&index) => {
+                    if depth < query_result[index].1 { query_result[index].1 = depth; }
+                },
+                None => {
+                    index_by_id.insert(id, query_result.len());
+                    query_result.push((object, depth));
+                }
+            }
+        }
+        self.record_query(query_result.len(), nodes_visited);
+        query_result
     }
-}
 
-impl TreeNode {
-    pub fn draw(&self) {
-        // Borders
-        draw_line(self.surface.x0 as f32, self.surface.y0 as f32, self.surface.x1 as f32, self.surface.y0 as f32, LINE_WIDTH, QUAD_LINES_COLOR);
-        draw_line(self.surface.x0 as f32, self.surface.y1 as f32, self.surface.x1 as f32, self.surface.y1 as f32, LINE_WIDTH, QUAD_LINES_COLOR);
-        draw_line(self.surface.x0 as f32, self.surface.y0 as f32, self.surface.x0 as f32, self.surface.y1 as f32, LINE_WIDTH, QUAD_LINES_COLOR);
-        draw_line(self.surface.x1 as f32, self.surface.y0 as f32, self.surface.x1 as f32, self.surface.y1 as f32, LINE_WIDTH, QUAD_LINES_COLOR);
+    // Number of matches per depth of the leaf they were found in, index 0 is unused since the root is at depth 1
+    // Straddling objects count once, at the shallowest depth, like query_objects_in_with_depth
+    pub fn matched_by_depth_in(&self, query: &Rectangle) -> Vec<usize> {
+        let mut counts = vec![0; self.deepest_node().max(0) as usize + 1];
+        for (_, depth) in self.query_objects_in_with_depth(query) {
+            counts[depth as usize] += 1;
+        }
+        counts
+    }
 
-        // Children
-        if self.objects.is_none() {
-            self.leaves.iter().for_each(|leaf| {
-                let leaf: &Box<TreeNode> = leaf.as_ref().unwrap();
-                leaf.draw();
-            })
-        } else {
-            let count  = self.objects.as_ref().unwrap().len().to_string();
-            draw_text(count.as_str(), self.surface.x0 as f32 + 2.0, self.surface.y0 as f32 + 10.0, 15.0, WHITE);
+    // Closest object by the Euclidean distance between (x, y) and its center, equally close objects go to the lowest id
+    pub fn nearest(&self, x: i32, y: i32) -> Option<Rc<RefCell<dyn QuadObject>>> {
+        self.nearest_with_distance(x, y).map(|(object, _)| object)
+    }
+
+    // Closest object by center, with the distance from (x, y) to that center, ties broken like nearest
+    pub fn nearest_with_distance(&self, x: i32, y: i32) -> Option<(Rc<RefCell<dyn QuadObject>>, f32)> {
+        let mut best = None;
+        let nodes_visited = self.top_node.nearest(x, y, &mut best);
+        self.record_query(best.is_some() as usize, nodes_visited);
+        best
+    }
+
+    // The k objects closest to (x, y) by center, closest first, equally close objects ordered by id like nearest
+    pub fn k_nearest(&self, x: i32, y: i32, k: usize) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut best = Vec::with_capacity(k + 1);
+        let nodes_visited = if k > 0 { self.top_node.k_nearest(x, y, k, &mut best) } else { 0 };
+        self.record_query(best.len(), nodes_visited);
+        best.into_iter().map(|(object, _)| object).collect()
+    }
+
+    // Objects with their center within `radius` of (cx, cy)
+    pub fn query_in_radius(&self, cx: i32, cy: i32, radius: i32) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut query_result = vec![];
+        let nodes_visited = self.top_node.query_by_radius(cx, cy, radius as f32, &mut query_result);
+        let query_result = dedup_by_id(query_result);
+        self.record_query(query_result.len(), nodes_visited);
+        query_result
+    }
+
+    // The others within radius of the object, served from the cache while the tree generation is unchanged
+    pub fn neighbours_cached(&self, object: &Rc<RefCell<dyn QuadObject>>, cache: &mut NeighbourCache, radius: i32) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        if cache.generation != self.generation {
+            cache.neighbours.clear();
+            cache.generation = self.generation;
+        }
+        let (id, (cx, cy)) = { let object = object.as_ref().borrow(); (object.get_id(), object.center()) };
+        if let Some(neighbours) = cache.neighbours.get(&(id, radius)) {
+            cache.hits += 1;
+            return neighbours.clone();
         }
+
+        cache.misses += 1;
+        let neighbours: Vec<Rc<RefCell<dyn QuadObject>>> = self.query_in_radius(cx, cy, radius).into_iter().filter(
+            |other| other.as_ref().borrow().get_id() != id
+        ).collect();
+        cache.neighbours.insert((id, radius), neighbours.clone());
+        neighbours
+    }
+
+    // Objects whose center is within L1 distance `dist`, the box around the diamond prunes the descent
+    pub fn query_manhattan(&self, cx: i32, cy: i32, dist: i32) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut query_result = vec![];
+        let query_surface = TreeSurface { x0: cx.saturating_sub(dist), y0: cy.saturating_sub(dist), x1: cx.saturating_add(dist), y1: cy.saturating_add(dist) };
+        let nodes_visited = self.top_node.query_overlapping(&query_surface, &mut query_result);
+        let query_result: Vec<Rc<RefCell<dyn QuadObject>>> = dedup_by_id(query_result).into_iter().filter(|object| {
+            let (x, y) = object.as_ref().borrow().center();
+            (x as i64 - cx as i64).abs() + (y as i64 - cy as i64).abs() <= dist as i64
+        }).collect();
+        self.record_query(query_result.len(), nodes_visited);
+        query_result
+    }
+
+    // Objects whose center lies between the two radii, both inclusive, pruned by the outer circle
+    pub fn query_in_annulus(&self, cx: i32, cy: i32, inner: i32, outer: i32) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        if inner > outer { return vec![]; }
+        self.query_in_radius(cx, cy, outer).into_iter().filter(
+            |object| point_distance((cx, cy), object.as_ref().borrow().center()) >= inner as f32
+        ).collect()
+    }
+
+    // Sum of 1 / (1 + distance) over the objects within `radius`, a cheap scalar field to sample
+    pub fn influence_at(&self, x: i32, y: i32, radius: i32) -> f32 {
+        self.query_in_radius(x, y, radius).iter().map(|object| {
+            1.0 / (1.0 + point_distance((x, y), object.as_ref().borrow().center()))
+        }).sum()
     }
-}
\ No newline at end of file
+
+    // Objects whose shape covers the point
+    pub fn query_point(&self, x: i32, y: i32) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut query_result = vec![];
+        let nodes_visited = self.top_node.query_by_point(&TreeSurface::from_size(x, y, x, y), &mut query_result);
+        let query_result = dedup_by_id(query_result);
+        self.record_query(query_result.len(), nodes_visited);
+        query_result
+    }
+
+    // Topmost object under the point, the highest id wins when shapes overlap
+    pub fn pick(&self, x: i32, y: i32) -> Option<Rc<RefCell<dyn QuadObject>>> {
+        self.query_point(x, y).into_iter().max_by_key(|object| object.as_ref().borrow().get_id())
+    }
+
+    // Stops descending as soon as `limit` unique objects are found
+    pub fn query_objects_in_limited(&self, query: &Rectangle, limit: usize) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let (mut query_result, mut seen, mut nodes_visited) = (vec![], HashSet::new(), 0);
+        if limit > 0 {
+            self.top_node.query_by_surface_limited(query, limit, &mut seen, &mut query_result, &mut nodes_visited);
+        }
+        self.record_query(query_result.len(), nodes_visited);
+        query_result
+    }
+
+    // First object overlapping the query that satisfies the predicate, traversal stops as soon as one is found
+    pub fn find_in<F: Fn(&Rc<RefCell<dyn QuadObject>>) -> bool>(&self, query: &Rectangle, predicate: F) -> Option<Rc<RefCell<dyn QuadObject>>> {
+        let mut nodes_visited = 0;
+        let found = self.top_node.find_in(&query.to_tree_surface(), &predicate, &mut nodes_visited);
+        self.record_query(found.is_some() as usize, nodes_visited);
+        found
+    }
+
+    // Convex hull of the centers of the objects overlapping the query, counter clockwise with y pointing up
+    pub fn hull_in(&self, query: &Rectangle) -> Vec<(i32, i32)> {
+        convex_hull(self.query_objects_in(query).iter().map(|object| object.as_ref().borrow().center()).collect())
+    }
+
+    // Hands each object overlapping the query to the sink once, without collecting them, stops when the sink returns false
+    pub fn query_objects_in_stream<S: FnMut(Rc<RefCell<dyn QuadObject>>) -> bool>(&self, query: &Rectangle, mut sink: S) {
+        let (mut seen, mut nodes_visited) = (HashSet::new(), 0);
+        self.top_node.stream_overlapping(&query.to_tree_surface(), &mut seen, &mut sink, &mut nodes_visited);
+        // Every object the sink was handed went into seen
+        self.record_query(seen.len(), nodes_visited);
+    }
+
+    // Objects whose bounds lie entirely inside the query, unlike query_surface which returns any overlap
+    pub fn query_contained_in(&self, query: &Rectangle) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut query_result = vec![];
+        let nodes_visited = self.top_node.query_contained(&query.to_tree_surface(), &mut query_result);
+        let query_result = dedup_by_id(query_result);
+        self.record_query(query_result.len(), nodes_visited);
+        query_result
+    }
+
+    // Objects overlapping the query split into (bounds inside the query, bounds reaching outside it), from one descent
+    pub fn classify_in(&self, query: &Rectangle) -> Classified {
+        let query_surface = query.to_tree_surface();
+        self.query_objects_in(query).into_iter().partition(|object| query_surface.contains(&object.as_ref().borrow().bounds()))
+    }
+
+    // Filtered objects overlapping the query per quadrant of the tree surface, [top left, top right, bottom left, bottom right]
+    // An object counts in the quadrant holding its center, whatever the fanout of the tree
+    pub fn quadrant_counts_in<F: Fn(&Rc<RefCell<dyn QuadObject>>) -> bool>(&self, query: &Rectangle, filter: F) -> [usize; 4] {
+        let (mx, my) = self.config.surface.mxy_at_depth(self.top_node.depth);
+        let mut counts = [0; 4];
+        for object in self.query_objects_in(query).iter().filter(|object| filter(object)) {
+            let (cx, cy) = object.as_ref().borrow().center();
+            counts[(cy >= my) as usize * 2 + (cx >= mx) as usize] += 1;
+        }
+        counts
+    }
+
+    // Objects the query finds over object copies it tests to find them, low when leaves are coarse or duplicated
+    pub fn query_precision(&self, query: &Rectangle) -> f32 {
+        // The same nodes query_objects_in looks into
+        let (mut hits, mut scanned) = (vec![], 0);
+        let nodes_visited = self.top_node.query_overlapping_counting(&query.to_tree_surface(), &mut hits, &mut scanned);
+        let hits = dedup_by_id(hits).len();
+        self.record_query(hits, nodes_visited);
+        if scanned == 0 { return 1.0; }
+
+        hits as f32 / scanned as f32
+    }
+
+    // Objects whose bounds do not touch the region, e.g. everything off screen
+    pub fn query_outside(&self, region: &Rectangle) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut query_result = vec![];
+        let nodes_visited = self.top_node.query_outside(&region.to_tree_surface(), &mut query_result);
+        let query_result = dedup_by_id(query_result);
+        self.record_query(query_result.len(), nodes_visited);
+        query_result
+    }
+
+    // Objects that went into more than one child of some node, the ones straddling a split line
+    pub fn objects_on_splits(&self) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut stored = vec![];
+        self.top_node.collect_objects(&mut stored);
+        let mut copies: HashMap<u32, usize> = HashMap::new();
+        for object in stored.iter() {
+            *copies.entry(object.as_ref().borrow().get_id()).or_insert(0) += 1;
+        }
+
+        // Under InsertPolicy::Enclosing straddlers are stored once, held by the node they straddle
+        let mut straddlers = vec![];
+        self.top_node.collect_held(&mut straddlers);
+        straddlers.extend(stored.into_iter().filter(|object| copies[&object.as_ref().borrow().get_id()] > 1));
+        dedup_by_id(straddlers)
+    }
+
+    // Rows of cells of cell_size pixels over the surface, true where some object's bounds reach the cell
+    // Panics when cell_size is not positive
+    pub fn occupancy_grid(&self, cell_size: i32) -> Vec<Vec<bool>> {
+        assert!(cell_size > 0, "occupancy_grid needs a positive cell size, got {}", cell_size);
+        let surface = self.config.surface;
+        // Offsets from the surface corner in i64, a surface can span more than i32::MAX pixels
+        let cell = |value: i32, origin: i32| ((value as i64 - origin as i64) / cell_size as i64) as usize;
+        let (cols, rows) = (cell(surface.x1, surface.x0) + 1, cell(surface.y1, surface.y0) + 1);
+        let mut grid = vec![vec![false; cols]; rows];
+
+        for object in self.unique_objects() {
+            let bounds = surface.intersection(&object.as_ref().borrow().bounds());
+            if bounds.is_degenerate() { continue; }
+            let (cols, rows) = (cell(bounds.x0, surface.x0)..=cell(bounds.x1, surface.x0), cell(bounds.y0, surface.y0)..=cell(bounds.y1, surface.y0));
+            for row in grid[rows].iter_mut() {
+                row[cols.clone()].iter_mut().for_each(|occupied| *occupied = true);
+            }
+        }
+        grid
+    }
+
+    // Every object once, sorted along a Z-order curve over their centers relative to the tree surface
+    pub fn objects_morton_order(&self) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+        let mut objects = self.unique_objects();
+        objects.sort_by_cached_key(|object| {
+            let (x, y) = object.as_ref().borrow().center();
+            // In i64, the offset across a surface wider than i32::MAX still fits a u32
+            morton_code((x as i64 - self.config.surface.x0 as i64).max(0) as u32, (y as i64 - self.config.surface.y0 as i64).max(0) as u32)
+        });
+        objects
+    }
+}
+impl<D: Default + Clone> TreeNode<D> {
+    fn estimate_count(&self, query_surface: &TreeSurface, max_depth: i32) -> f32 {
+        if !query_surface.intersects(&self.surface) { return 0.0; }
+
+        let covered = self.surface.intersection(query_surface).area() as f32 / self.surface.area().max(1) as f32;
+        if self.objects.is_none() && self.depth < max_depth {
+            let in_leaves: f32 = self.leaves.iter().map(|leaf| leaf.estimate_count(query_surface, max_depth)).sum();
+            return in_leaves + self.held.len() as f32 * covered;
+        }
+        self.object_count() as f32 * covered
+    }
+
+    fn enclosing_node(&self, query_surface: &TreeSurface) -> &TreeNode<D> {
+        match self.leaves.iter().find(|leaf| leaf.surface.contains(query_surface)) {
+            Some(leaf) => leaf.enclosing_node(query_surface),
+            None => self,
+        }
+    }
+    fn enclosing_node_mut(&mut self, query_surface: &TreeSurface) -> &mut TreeNode<D> {
+        match self.leaves.iter().position(|leaf| leaf.surface.contains(query_surface)) {
+            Some(index) => self.leaves[index].enclosing_node_mut(query_surface),
+            None => self,
+        }
+    }
+
+    fn leaves_in(&self, query_surface: &TreeSurface, surfaces: &mut Vec<TreeSurface>) -> usize {
+        if !query_surface.intersects(&self.surface) { return 0; }
+
+        if self.objects.is_some() {
+            surfaces.push(self.surface);
+        }
+        1 + self.leaves.iter().map(|leaf| leaf.leaves_in(query_surface, surfaces)).sum::<usize>()
+    }
+
+    // Every object stored in a node the surface touches, whether or not the object itself does
+    // The query helpers return how many nodes they looked into, for QueryStats
+    fn collect_touching(&self, query_surface: &TreeSurface, query_result: &mut Vec<Rc<RefCell<dyn QuadObject>>>) -> usize {
+        if !query_surface.intersects(&self.surface) { return 0; }
+
+        query_result.extend(self.stored().iter().cloned());
+        1 + self.leaves.iter().map(|leaf| leaf.collect_touching(query_surface, query_result)).sum::<usize>()
+    }
+
+    fn query_overlapping(&self, query_surface: &TreeSurface, query_result: &mut Vec<Rc<RefCell<dyn QuadObject>>>) -> usize {
+        if !self.may_hold(query_surface) { return 0; }
+
+        for object in self.stored().iter() {
+            if object.as_ref().borrow().is_overlap(query_surface) { query_result.push(Rc::clone(object)) }
+        }
+        1 + self.leaves.iter().map(|leaf| leaf.query_overlapping(query_surface, query_result)).sum::<usize>()
+    }
+
+    // query_overlapping that also counts the object copies it tests
+    fn query_overlapping_counting(&self, query_surface: &TreeSurface, query_result: &mut Vec<Rc<RefCell<dyn QuadObject>>>, scanned: &mut usize) -> usize {
+        if !self.may_hold(query_surface) { return 0; }
+
+        let stored = self.stored();
+        *scanned += stored.len();
+        for object in stored.iter() {
+            if object.as_ref().borrow().is_overlap(query_surface) { query_result.push(Rc::clone(object)) }
+        }
+        1 + self.leaves.iter().map(|leaf| leaf.query_overlapping_counting(query_surface, query_result, scanned)).sum::<usize>()
+    }
+
+    // query_overlapping for trees with sorted_leaves, skips the leaf objects ending left of the query
+    fn query_overlapping_sorted(&self, query_surface: &TreeSurface, query_result: &mut Vec<Rc<RefCell<dyn QuadObject>>>) -> usize {
+        if !self.may_hold(query_surface) { return 0; }
+
+        let stored = match &self.objects {
+            Some(objects) => &objects[objects.partition_point(|object| object.as_ref().borrow().bounds().x1 < query_surface.x0)..],
+            None => &self.held[..],
+        };
+        for object in stored.iter() {
+            if object.as_ref().borrow().is_overlap(query_surface) { query_result.push(Rc::clone(object)) }
+        }
+        1 + self.leaves.iter().map(|leaf| leaf.query_overlapping_sorted(query_surface, query_result)).sum::<usize>()
+    }
+
+    fn query_by_radius(&self, cx: i32, cy: i32, radius: f32, query_result: &mut Vec<Rc<RefCell<dyn QuadObject>>>) -> usize {
+        if self.surface.distance_to(cx, cy) > radius { return 0; }
+
+        for object in self.stored().iter() {
+            if point_distance((cx, cy), object.as_ref().borrow().center()) <= radius { query_result.push(Rc::clone(object)) }
+        }
+        1 + self.leaves.iter().map(|leaf| leaf.query_by_radius(cx, cy, radius, query_result)).sum::<usize>()
+    }
+
+    fn query_by_point(&self, point: &TreeSurface, query_result: &mut Vec<Rc<RefCell<dyn QuadObject>>>) -> usize {
+        if !self.surface.contains(point) { return 0; }
+
+        for object in self.stored().iter() {
+            if object.as_ref().borrow().is_overlap(point) { query_result.push(Rc::clone(object)) }
+        }
+        1 + self.leaves.iter().map(|leaf| leaf.query_by_point(point, query_result)).sum::<usize>()
+    }
+
+    // Returns true once the limit is reached so callers stop descending, visited nodes are counted in nodes_visited instead
+    fn query_by_surface_limited(&self, query_surface: &Rectangle, limit: usize, seen: &mut HashSet<u32>, query_result: &mut Vec<Rc<RefCell<dyn QuadObject>>>, nodes_visited: &mut usize) -> bool {
+        if !self.may_hold(&query_surface.to_tree_surface()) { return false; }
+        *nodes_visited += 1;
+
+        for object in self.stored().iter() {
+            if query_surface.is_rect_overlap(object) && seen.insert(object.as_ref().borrow().get_id()) {
+                query_result.push(Rc::clone(object));
+                if query_result.len() >= limit { return true; }
+            }
+        }
+        self.leaves.iter().any(|leaf| leaf.query_by_surface_limited(query_surface, limit, seen, query_result, nodes_visited))
+    }
+
+    fn nearest(&self, x: i32, y: i32, best: &mut Option<(Rc<RefCell<dyn QuadObject>>, f32)>) -> usize {
+        // Prune with the same metric the objects are measured with
+        if let Some((_, best_distance)) = best {
+            if self.surface.distance_to(x, y) > *best_distance { return 0; }
+        }
+
+        for object in self.stored().iter() {
+            let (id, distance) = { let object = object.as_ref().borrow(); (object.get_id(), point_distance((x, y), object.center())) };
+            // Equal distances go to the lowest id, so the result does not depend on traversal order
+            let is_closer = match best {
+                Some((best_object, best_distance)) => distance < *best_distance || (distance == *best_distance && id < best_object.as_ref().borrow().get_id()),
+                None => true,
+            };
+            if is_closer { *best = Some((Rc::clone(object), distance)); }
+        }
+        // Closest leaves first so the bound tightens early
+        let mut leaves: Vec<&TreeNode<D>> = self.leaves.iter().collect();
+        leaves.sort_by(|a, b| a.surface.distance_to(x, y).total_cmp(&b.surface.distance_to(x, y)));
+        1 + leaves.into_iter().map(|leaf| leaf.nearest(x, y, best)).sum::<usize>()
+    }
+
+    // Keeps best sorted by (distance, id) and at most k long, objects in several leaves are only added once
+    fn k_nearest(&self, x: i32, y: i32, k: usize, best: &mut Vec<(Rc<RefCell<dyn QuadObject>>, f32)>) -> usize {
+        if best.len() == k && self.surface.distance_to(x, y) > best[k - 1].1 { return 0; }
+
+        for object in self.stored().iter() {
+            let (id, distance) = { let object = object.as_ref().borrow(); (object.get_id(), point_distance((x, y), object.center())) };
+            let best_key = |(object, distance): &(Rc<RefCell<dyn QuadObject>>, f32)| (*distance, object.as_ref().borrow().get_id());
+            if best.iter().any(|entry| best_key(entry).1 == id) { continue; }
+            let index = best.partition_point(|entry| best_key(entry) < (distance, id));
+            if index < k {
+                best.insert(index, (Rc::clone(object), distance));
+                best.truncate(k);
+            }
+        }
+        let mut leaves: Vec<&TreeNode<D>> = self.leaves.iter().collect();
+        leaves.sort_by(|a, b| a.surface.distance_to(x, y).total_cmp(&b.surface.distance_to(x, y)));
+        1 + leaves.into_iter().map(|leaf| leaf.k_nearest(x, y, k, best)).sum::<usize>()
+    }
+
+    // Returns false once the sink asked to stop so callers stop descending, visited nodes are counted in nodes_visited instead
+    fn stream_overlapping<S: FnMut(Rc<RefCell<dyn QuadObject>>) -> bool>(&self, query_surface: &TreeSurface, seen: &mut HashSet<u32>, sink: &mut S, nodes_visited: &mut usize) -> bool {
+        if !self.may_hold(query_surface) { return true; }
+        *nodes_visited += 1;
+
+        for object in self.stored().iter() {
+            let (id, overlaps) = { let object = object.as_ref().borrow(); (object.get_id(), object.is_overlap(query_surface)) };
+            if overlaps && seen.insert(id) && !sink(Rc::clone(object)) { return false; }
+        }
+        self.leaves.iter().all(|leaf| leaf.stream_overlapping(query_surface, seen, sink, nodes_visited))
+    }
+    fn find_in<F: Fn(&Rc<RefCell<dyn QuadObject>>) -> bool>(&self, query_surface: &TreeSurface, predicate: &F, nodes_visited: &mut usize) -> Option<Rc<RefCell<dyn QuadObject>>> {
+        if !self.may_hold(query_surface) { return None; }
+        *nodes_visited += 1;
+
+        let found = self.stored().iter().find(|object| object.as_ref().borrow().is_overlap(query_surface) && predicate(object));
+        if let Some(object) = found { return Some(Rc::clone(object)); }
+        self.leaves.iter().find_map(|leaf| leaf.find_in(query_surface, predicate, nodes_visited))
+    }
+
+    fn query_contained(&self, query_surface: &TreeSurface, query_result: &mut Vec<Rc<RefCell<dyn QuadObject>>>) -> usize {
+        if !self.may_hold(query_surface) { return 0; }
+
+        for object in self.stored().iter() {
+            if query_surface.contains(&object.as_ref().borrow().bounds()) { query_result.push(Rc::clone(object)) }
+        }
+        1 + self.leaves.iter().map(|leaf| leaf.query_contained(query_surface, query_result)).sum::<usize>()
+    }
+
+    fn query_outside(&self, region: &TreeSurface, query_result: &mut Vec<Rc<RefCell<dyn QuadObject>>>) -> usize {
+        // Everything stored below a node inside the region overlaps it
+        if region.contains(&self.surface) { return 0; }
+
+        for object in self.stored().iter() {
+            if !region.intersects(&object.as_ref().borrow().bounds()) { query_result.push(Rc::clone(object)) }
+        }
+        1 + self.leaves.iter().map(|leaf| leaf.query_outside(region, query_result)).sum::<usize>()
+    }
+
+    fn query_by_surface_with_depth(&self, query_surface: &Rectangle, query_result: &mut Vec<(Rc<RefCell<dyn QuadObject>>, i32)>) -> usize {
+        if !self.may_hold(&query_surface.to_tree_surface()) { return 0; }
+
+        for object in self.stored().iter() {
+            if query_surface.is_rect_overlap(object) { query_result.push((Rc::clone(object), self.depth)) }
+        }
+        1 + self.leaves.iter().map(|leaf| leaf.query_by_surface_with_depth(query_surface, query_result)).sum::<usize>()
+    }
+
+    // Does not prune, it looks into every node
+    pub fn query_by_surface(&self, query_surface: &Rectangle, query_result: &mut Vec<Rc<RefCell<dyn QuadObject>>>) -> usize {
+        if self.objects.is_some() { // Check if objectvector is not None
+            for object in self.objects.as_ref().unwrap().iter() {
+                if query_surface.is_rect_overlap(object.borrow()) { query_result.push(Rc::clone(object)) }
+            }
+            1
+        } else {
+            for object in self.held.iter() {
+                if query_surface.is_rect_overlap(object) { query_result.push(Rc::clone(object)) }
+            }
+            1 + self.leaves.iter().map(|leaf| leaf.query_by_surface(query_surface, query_result)).sum::<usize>()
+        }
+    }
+
+    pub fn query_by_object(&self, query_object: &Rc<RefCell<dyn QuadObject>>, k: Option<i32>, query_result: &mut Vec<Rc<RefCell<dyn QuadObject>>>) -> usize {
+        let query_id = query_object.as_ref().borrow().get_id();
+        if self.objects.is_some() {
+            // If there are objects in vector then we return the vector
+            // But, we cannot return the query object
+            // So, we need to check if object is self
+            let all_objects = self.objects.as_ref().unwrap();
+            query_result.extend(all_objects.iter().filter(|object| object.as_ref().borrow().get_id() != query_id).cloned());
+            return 1
+        }
+
+        // Else, the real shit begins
+        // Objects held at this node straddle the leaves, so they neighbour everything below
+        query_result.extend(self.held.iter().filter(
+            |object| object.as_ref().borrow().get_id() != query_id
+        ).cloned());
+
+        // Loop through leaves, if leaf contains the object then query as well
+        let mut nodes_visited = 1;
+        for node in self.leaves.iter() {
+            if node.contains_object(query_object) {
+                nodes_visited += node.query_by_object(query_object, k, query_result)
+            }
+        }
+        // Resulting vector should give all nodes which are in the same nodes as the query_object
+        // Further filtering now
+        return nodes_visited
+    }
+}
+
+// ----------------------------------------
+// Draw Functions
+// ----------------------------------------
+// Which text overlays to draw, borders are always drawn
+#[derive(Clone, Copy)]
+pub struct DrawStyle {
+    pub node_counts: bool, // Object count in every leaf
+    pub info: bool, // Summary lines next to the tree
+}
+
+impl Default for DrawStyle {
+    fn default() -> DrawStyle {
+        DrawStyle { node_counts: true, info: true }
+    }
+}
+
+impl<D: Default + Clone> QuadTree<D> {
+    pub fn draw(&self, style: &DrawStyle) {
+        // Borders
+        draw_line(self.config.surface.x0 as f32, self.config.surface.y0 as f32, self.config.surface.x1 as f32, self.config.surface.y0 as f32, LINE_WIDTH, DARKGRAY);
+        draw_line(self.config.surface.x0 as f32, self.config.surface.y1 as f32, self.config.surface.x1 as f32, self.config.surface.y1 as f32, LINE_WIDTH, DARKGRAY);
+        draw_line(self.config.surface.x0 as f32, self.config.surface.y0 as f32, self.config.surface.x0 as f32, self.config.surface.y1 as f32, LINE_WIDTH, DARKGRAY);
+        draw_line(self.config.surface.x1 as f32, self.config.surface.y0 as f32, self.config.surface.x1 as f32, self.config.surface.y1 as f32, LINE_WIDTH, DARKGRAY);
+
+        // Children
+        self.top_node.draw(style);
+
+        // Text
+        if !style.info { return; }
+        let draw_x = self.config.surface.x1 as f32 + 5.0;
+        let node_count = self.node_count();
+        let empty_nodes = self.empty_node_count();
+
+        let mut info_str = String::from("Node count:");
+        info_str.push_str(&node_count.to_string());
+        draw_text(info_str.as_str(), draw_x, 40.0, 15.0, WHITE);
+        info_str.clear();
+
+        info_str.push_str("Empty nodes:");
+        info_str.push_str(&empty_nodes.to_string());
+        info_str.push_str(" / ");
+        info_str.push_str(&node_count.to_string());
+        info_str.push_str(" -> ");
+        info_str.push_str(&(100.0 * empty_nodes as f32 / node_count as f32).to_string());
+        info_str.push_str("%");
+        draw_text(info_str.as_str(), draw_x, 60.0, 15.0, WHITE);
+        info_str.clear();
+
+        info_str.push_str("Deepest node:");
+        info_str.push_str(&self.deepest_node().to_string());
+        draw_text(info_str.as_str(), draw_x, 80.0, 15.0, WHITE);
+        info_str.clear();
+
+        info_str.push_str("Objects in nodes:");
+        info_str.push_str(&self.object_count().to_string());
+        draw_text(info_str.as_str(), draw_x, 100.0, 15.0, WHITE);
+        info_str.clear();
+
+        info_str.push_str("Allocated nodes:");
+        info_str.push_str(&self.top_node.leaf_count().to_string());
+        draw_text(info_str.as_str(), draw_x, 120.0, 15.0, WHITE);
+        info_str.clear();
+
+        info_str.push_str("Max objects in nodes:");
+        info_str.push_str(&self.top_node.max_objects().to_string());
+        draw_text(info_str.as_str(), draw_x, 140.0, 15.0, WHITE);
+        info_str.clear();
+    }
+}
+
+// ----------------------------------------
+// SVG Export
+// ----------------------------------------
+impl<D: Default + Clone> QuadTree<D> {
+    // One <rect> per node border followed by one element per object, for viewing outside the window
+    pub fn to_svg(&self, objects: &[Rc<RefCell<dyn QuadObject>>]) -> String {
+        let mut svg = String::new();
+        let (w, h) = (self.config.surface.x1 as i64 - self.config.surface.x0 as i64, self.config.surface.y1 as i64 - self.config.surface.y0 as i64);
+        writeln!(svg, "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">", self.config.surface.x0, self.config.surface.y0, w, h).unwrap();
+
+        // Nodes
+        self.top_node.to_svg(&mut svg);
+
+        // Objects
+        for object in objects {
+            writeln!(svg, "{}", object.as_ref().borrow().svg_element()).unwrap();
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+impl<D: Default + Clone> TreeNode<D> {
+    fn to_svg(&self, svg: &mut String) {
+        let (w, h) = (self.surface.x1 as i64 - self.surface.x0 as i64, self.surface.y1 as i64 - self.surface.y0 as i64);
+        writeln!(svg, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"gray\"/>", self.surface.x0, self.surface.y0, w, h).unwrap();
+
+        for leaf in self.leaves.iter() {
+            leaf.to_svg(svg);
+        }
+    }
+}
+
+impl<D: Default + Clone> TreeNode<D> {
+    pub fn draw(&self, style: &DrawStyle) {
+        // Borders
+        draw_line(self.surface.x0 as f32, self.surface.y0 as f32, self.surface.x1 as f32, self.surface.y0 as f32, LINE_WIDTH, QUAD_LINES_COLOR);
+        draw_line(self.surface.x0 as f32, self.surface.y1 as f32, self.surface.x1 as f32, self.surface.y1 as f32, LINE_WIDTH, QUAD_LINES_COLOR);
+        draw_line(self.surface.x0 as f32, self.surface.y0 as f32, self.surface.x0 as f32, self.surface.y1 as f32, LINE_WIDTH, QUAD_LINES_COLOR);
+        draw_line(self.surface.x1 as f32, self.surface.y0 as f32, self.surface.x1 as f32, self.surface.y1 as f32, LINE_WIDTH, QUAD_LINES_COLOR);
+
+        // Children
+        if self.objects.is_none() {
+            self.leaves.iter().for_each(|leaf| {
+                leaf.draw(style);
+            })
+        } else if style.node_counts {
+            let count  = self.objects.as_ref().unwrap().len().to_string();
+            draw_text(count.as_str(), self.surface.x0 as f32 + 2.0, self.surface.y0 as f32 + 10.0, 15.0, WHITE);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::f32::consts::PI;
+    use crate::quad_objects::{Boid, Circle, ObjectKind, QuadObject, Rectangle};
+    use crate::scene::generate_scene;
+    use crate::test_object::TestObject;
+    use super::{assign_object_to_grid, convex_hull, Adjacency, CapacityScaling, InsertPolicy, NeighbourCache, OutOfBounds, OutOfBoundsPolicy, QuadTree, SplitMode, TreeConfig, TreeSurface};
+
+    fn shared<T: QuadObject + 'static>(object: T) -> Rc<RefCell<dyn QuadObject>> {
+        Rc::new(RefCell::new(object))
+    }
+
+    #[test]
+    fn query_with_depth_reports_the_leaf_depth() {
+        // Capacity 1 keeps splitting until the two close boids are apart
+        let mut tree = QuadTree::with_capacity(0, 0, 500, 500, 1);
+        let deep = shared(Boid::new(0, 10, 10, 0.0));
+        tree.insert_object(Rc::clone(&deep)).unwrap();
+        tree.insert_object(shared(Boid::new(1, 12, 12, 0.0))).unwrap();
+        tree.insert_object(shared(Boid::new(2, 400, 400, 0.0))).unwrap();
+
+        let found = tree.query_objects_in_with_depth(&Rectangle::new(9, 9, 9, 2, 2));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].1, tree.path_to(&deep)[0].len() as i32 + 1);
+        assert!(found[0].1 > 3);
+    }
+
+    fn ids(objects: &[Rc<RefCell<dyn QuadObject>>]) -> Vec<u32> {
+        objects.iter().map(|object| object.as_ref().borrow().get_id()).collect()
+    }
+
+    #[test]
+    fn compact_ids_makes_sparse_ids_dense() {
+        let mut objects = vec![shared(Boid::new(7, 10, 10, 0.0)), shared(Circle::new(42, 200, 200, 5)), shared(Rectangle::new(1000, 300, 300, 10, 10))];
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        tree.compact_ids(&mut objects);
+
+        assert_eq!(ids(&objects), vec![0, 1, 2]);
+        assert_eq!(ids(&tree.objects_sorted_by_id()), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn query_contained_in_leaves_out_partial_overlaps() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        tree.insert_object(shared(Circle::new(0, 150, 150, 10))).unwrap();
+        tree.insert_object(shared(Circle::new(1, 195, 150, 10))).unwrap(); // Reaches x = 205, past the query
+
+        let query = Rectangle::new(2, 100, 100, 100, 100);
+        assert_eq!(ids(&tree.query_contained_in(&query)), vec![0]);
+        assert_eq!(tree.query_objects_in(&query).len(), 2);
+    }
+
+    #[test]
+    fn nearest_with_distance_measures_to_the_center() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        tree.insert_object(shared(Boid::new(0, 130, 140, 0.0))).unwrap();
+        tree.insert_object(shared(Circle::new(1, 400, 400, 20))).unwrap();
+
+        // A 3-4-5 triangle scaled by 10
+        let (object, distance) = tree.nearest_with_distance(100, 100).unwrap();
+        assert_eq!(object.as_ref().borrow().get_id(), 0);
+        assert_eq!(distance, 50.0);
+    }
+
+    // 100 probes 5 pixels apart in the square from (100, 100) to (145, 145)
+    fn probe_grid(checks: &Arc<AtomicUsize>) -> QuadTree {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        for id in 0..100 {
+            tree.insert_object(TestObject::point(id, 100 + (id % 10) as i32 * 5, 100 + (id / 10) as i32 * 5).counting(checks).shared()).unwrap();
+        }
+        checks.store(0, Ordering::Relaxed);
+        tree
+    }
+
+    #[test]
+    fn query_limited_stops_descending_at_the_limit() {
+        let checks = Arc::new(AtomicUsize::new(0));
+        let tree = probe_grid(&checks);
+        let query = Rectangle::new(100, 90, 90, 60, 60);
+
+        assert_eq!(tree.query_objects_in_limited(&query, 2).len(), 2);
+        let limited_checks = checks.load(Ordering::Relaxed);
+        checks.store(0, Ordering::Relaxed);
+        assert_eq!(tree.query_objects_in(&query).len(), 100);
+        assert!(limited_checks < 10 && limited_checks < checks.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn odd_surfaces_split_into_balanced_children() {
+        // 751 x 551 pixels, edges inclusive
+        let mut tree = QuadTree::with_capacity(0, 0, 750, 550, 1);
+        tree.insert_object(shared(Boid::new(0, 10, 10, 0.0))).unwrap();
+        tree.insert_object(shared(Boid::new(1, 700, 500, 0.0))).unwrap();
+
+        let children: Vec<TreeSurface> = tree.nodes_breadth_first()[1..5].iter().map(|node| node.surface).collect();
+        let (width, height) = (|surface: &TreeSurface| surface.x1 - surface.x0 + 1, |surface: &TreeSurface| surface.y1 - surface.y0 + 1);
+        assert!((width(&children[0]) - width(&children[1])).abs() <= 1);
+        assert!((height(&children[0]) - height(&children[2])).abs() <= 1);
+        assert_eq!(width(&children[0]) + width(&children[1]), 751);
+        assert_eq!(height(&children[0]) + height(&children[2]), 551);
+        assert_eq!((children[0].x1 + 1, children[0].y1 + 1), (children[1].x0, children[2].y0));
+
+        // The leftover pixel changes sides from one depth to the next
+        let surface = TreeSurface::from_size(0, 0, 750, 550);
+        assert_ne!(surface.mxy_at_depth(1), surface.mxy_at_depth(2));
+    }
+
+    #[test]
+    fn svg_has_a_rect_per_node_and_per_rectangle() {
+        // Two boids in different quadrants split the root once, 5 nodes
+        let mut tree = QuadTree::with_capacity(0, 0, 500, 500, 1);
+        let objects = vec![shared(Boid::new(0, 10, 10, 0.0)), shared(Boid::new(1, 400, 400, 0.0)), shared(Rectangle::new(2, 300, 50, 20, 20))];
+        tree.insert_object(Rc::clone(&objects[0])).unwrap();
+        tree.insert_object(Rc::clone(&objects[1])).unwrap();
+        assert_eq!(tree.node_count(), 5);
+
+        let svg = tree.to_svg(&objects);
+        assert_eq!(svg.matches("<rect").count(), 6);
+        assert_eq!(svg.matches("<polygon").count(), 2);
+    }
+
+    #[test]
+    fn pick_returns_the_highest_id_under_the_point() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        tree.insert_object(shared(Circle::new(8, 110, 100, 20))).unwrap();
+        tree.insert_object(shared(Circle::new(3, 100, 100, 20))).unwrap();
+
+        assert_eq!(tree.pick(105, 100).unwrap().as_ref().borrow().get_id(), 8);
+        assert_eq!(tree.pick(85, 100).unwrap().as_ref().borrow().get_id(), 3);
+        assert!(tree.pick(300, 300).is_none());
+    }
+
+    #[test]
+    fn influence_falls_off_with_distance() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        tree.insert_object(shared(Boid::new(0, 200, 200, 0.0))).unwrap();
+
+        let samples: Vec<f32> = [200, 230, 260, 290].iter().map(|&x| tree.influence_at(x, 200, 100)).collect();
+        assert_eq!(samples[0], 1.0);
+        assert!(samples.windows(2).all(|pair| pair[0] > pair[1]));
+        assert_eq!(tree.influence_at(350, 200, 100), 0.0);
+    }
+
+    #[test]
+    fn builder_capacity_and_max_depth_take_effect() {
+        let mut tree = QuadTree::builder().bounds(0, 0, 500, 500).capacity(3).max_depth(2).build();
+        assert_eq!((tree.capacity(), tree.max_depth()), (3, 2));
+
+        for id in 0..3 {
+            tree.insert_object(shared(Boid::new(id, 10 + id as i32, 10, 0.0))).unwrap();
+        }
+        assert_eq!(tree.node_count(), 1);
+        tree.insert_object(shared(Boid::new(3, 13, 10, 0.0))).unwrap();
+        assert_eq!(tree.node_count(), 5);
+
+        // The crowded corner can not split past depth 2
+        for id in 4..30 {
+            tree.insert_object(shared(Boid::new(id, 10 + id as i32, 10, 0.0))).unwrap();
+        }
+        assert_eq!((tree.deepest_node(), tree.node_count()), (2, 5));
+    }
+
+    #[test]
+    fn moved_callback_reports_ids_and_centers() {
+        let mut tree = QuadTree::with_capacity(0, 0, 500, 500, 1);
+        let object = shared(Boid::new(0, 10, 10, 0.0));
+        tree.insert_object(Rc::clone(&object)).unwrap();
+        tree.insert_object(shared(Boid::new(1, 400, 400, 0.0))).unwrap();
+
+        let moves = Rc::new(RefCell::new(vec![]));
+        let recorded = Rc::clone(&moves);
+        tree.set_on_object_moved(Some(Box::new(move |id, old, new| recorded.borrow_mut().push((id, old, new)))));
+
+        // Within the same leaf, no call
+        object.as_ref().borrow_mut().translate(0, 5);
+        tree.update_object(&object);
+        assert!(moves.borrow().is_empty());
+
+        object.as_ref().borrow_mut().translate(290, 85);
+        tree.update_object(&object);
+        assert_eq!(*moves.borrow(), vec![(0, (10, 15), (300, 100))]);
+    }
+
+    #[test]
+    fn insertion_order_query_sorts_ids_ascending() {
+        let mut tree = QuadTree::with_capacity(0, 0, 500, 500, 2);
+        for id in 0..20 {
+            // Scattered so the leaf order differs from the id order
+            tree.insert_object(shared(Boid::new(id, (id as i32 * 173) % 480 + 10, (id as i32 * 97) % 480 + 10, 0.0))).unwrap();
+        }
+
+        let query = Rectangle::new(20, 0, 0, 500, 500);
+        assert_ne!(ids(&tree.query_objects_in(&query)), (0..20).collect::<Vec<u32>>());
+        assert_eq!(ids(&tree.query_objects_in_insertion_order(&query)), (0..20).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn join_reports_only_truly_overlapping_pairs() {
+        let mut projectiles = QuadTree::new(0, 0, 500, 500);
+        let mut enemies = QuadTree::new(0, 0, 500, 500);
+        for projectile in [shared(Circle::new(0, 100, 100, 10)), shared(Circle::new(1, 200, 200, 10)), shared(Boid::new(2, 300, 300, 0.0)), shared(Boid::new(3, 50, 450, 0.0))] {
+            projectiles.insert_object(projectile).unwrap();
+        }
+        // 100 only meets the bounding box of 0
+        for enemy in [shared(Circle::new(100, 115, 115, 10)), shared(Circle::new(101, 214, 200, 5)), shared(Rectangle::new(102, 290, 290, 20, 20))] {
+            enemies.insert_object(enemy).unwrap();
+        }
+
+        let mut pairs = vec![];
+        projectiles.join(&enemies, |projectile, enemy| pairs.push((projectile.as_ref().borrow().get_id(), enemy.as_ref().borrow().get_id())));
+        pairs.sort();
+        assert_eq!(pairs, vec![(1, 101), (2, 102)]);
+    }
+
+    #[test]
+    fn clamp_objects_moves_an_escaped_boid_onto_the_edge() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        let mut objects = vec![shared(Boid::new(0, 600, 250, 0.0)), shared(Boid::new(1, 250, -30, 0.0))];
+        tree.clamp_objects(&mut objects);
+
+        assert_eq!(objects[0].as_ref().borrow().center(), (500, 250));
+        assert_eq!(objects[1].as_ref().borrow().center(), (250, 0));
+        assert_eq!(ids(&tree.query_point(500, 250)), vec![0]);
+    }
+
+    #[test]
+    fn generation_advances_on_mutations_only() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        let object = shared(Boid::new(0, 10, 10, 0.0));
+        let mut last = tree.generation();
+        let mut advanced = |tree: &QuadTree| { let moved = tree.generation() > last; last = tree.generation(); moved };
+
+        tree.insert_object(Rc::clone(&object)).unwrap();
+        assert!(advanced(&tree));
+        tree.update_object(&object);
+        assert!(advanced(&tree));
+        tree.query_objects_in(&Rectangle::new(1, 0, 0, 500, 500));
+        tree.query_in_radius(10, 10, 50);
+        tree.nearest(0, 0);
+        assert!(!advanced(&tree));
+        tree.remove_object(0);
+        assert!(advanced(&tree));
+        tree.clear();
+        assert!(advanced(&tree));
+    }
+
+    #[test]
+    fn translated_rectangle_moves_to_new_leaves() {
+        let mut tree = QuadTree::with_capacity(0, 0, 500, 500, 1);
+        let rectangle = shared(Rectangle::new(0, 10, 10, 20, 20));
+        tree.insert_object(Rc::clone(&rectangle)).unwrap();
+        tree.insert_object(shared(Boid::new(1, 400, 400, 0.0))).unwrap();
+        assert_eq!(tree.path_to(&rectangle), vec![vec![0]]);
+
+        rectangle.as_ref().borrow_mut().translate(300, 5);
+        let bounds = rectangle.as_ref().borrow().bounds();
+        assert_eq!((bounds.x0, bounds.y0, bounds.x1, bounds.y1), (310, 15, 330, 35));
+        tree.update_object(&rectangle);
+        assert_eq!(tree.path_to(&rectangle), vec![vec![1]]);
+    }
+
+    fn corners(surfaces: &[TreeSurface]) -> Vec<(i32, i32, i32, i32)> {
+        surfaces.iter().map(|surface| (surface.x0, surface.y0, surface.x1, surface.y1)).collect()
+    }
+
+    #[test]
+    fn leaves_in_returns_the_touched_leaf_surfaces() {
+        // One split of the 501 x 501 root, the odd pixel goes to the first half at depth 1
+        let mut tree = QuadTree::with_capacity(0, 0, 500, 500, 1);
+        tree.insert_object(shared(Boid::new(0, 10, 10, 0.0))).unwrap();
+        tree.insert_object(shared(Boid::new(1, 400, 400, 0.0))).unwrap();
+
+        assert_eq!(corners(&tree.leaves_in(&Rectangle::new(2, 300, 300, 10, 10))), vec![(251, 251, 500, 500)]);
+        assert_eq!(corners(&tree.leaves_in(&Rectangle::new(2, 245, 100, 10, 10))), vec![(0, 0, 250, 250), (251, 0, 500, 250)]);
+        assert_eq!(tree.leaves_in(&Rectangle::new(2, 245, 245, 10, 10)).len(), 4);
+    }
+
+    #[test]
+    fn four_by_four_split_assigns_boundary_objects() {
+        // 400 x 400 pixels, columns and rows start at 0, 100, 200 and 300
+        let surface = TreeSurface::from_size(0, 0, 399, 399);
+        let cells = |object: &dyn QuadObject| assign_object_to_grid(&surface, 1, 4, object);
+
+        assert_eq!(cells(&Boid::new(0, 99, 50, 0.0)), vec![0]);
+        assert_eq!(cells(&Boid::new(0, 100, 50, 0.0)), vec![1]);
+        assert_eq!(cells(&Boid::new(0, 399, 399, 0.0)), vec![15]);
+        assert_eq!(cells(&Rectangle::new(0, 95, 95, 10, 10)), vec![0, 1, 4, 5]);
+        assert_eq!(cells(&Rectangle::new(0, 250, 310, 100, 0)), vec![14, 15]);
+        // Centered on a corner, the circle reaches into all four cells around it
+        assert_eq!(cells(&Circle::new(0, 200, 200, 5)), vec![5, 6, 9, 10]);
+
+        let mut tree = QuadTree::builder().bounds(0, 0, 399, 399).fanout(4).capacity(1).build();
+        let straddler = shared(Rectangle::new(0, 95, 95, 10, 10));
+        tree.insert_object(Rc::clone(&straddler)).unwrap();
+        tree.insert_object(shared(Boid::new(1, 350, 350, 0.0))).unwrap();
+        assert_eq!(tree.node_count(), 17);
+        assert_eq!(tree.path_to(&straddler), vec![vec![0], vec![1], vec![4], vec![5]]);
+    }
+
+    // A 1000 x 1000 tree holding generate_scene
+    fn scene_tree(count: usize, seed: u64) -> QuadTree {
+        let mut tree = QuadTree::new(0, 0, 1000, 1000);
+        let surface = *tree.get_surface();
+        for object in generate_scene(count, seed, &surface) {
+            tree.insert_object(object).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn estimate_count_in_is_close_on_a_uniform_scene() {
+        let tree = scene_tree(2000, 3);
+        for query in [Rectangle::new(0, 200, 200, 300, 300), Rectangle::new(0, 0, 0, 500, 1000), Rectangle::new(0, 600, 100, 150, 700)] {
+            let (estimate, actual) = (tree.estimate_count_in(&query) as f32, tree.query_objects_in(&query).len() as f32);
+            assert!(estimate > actual * 0.5 && estimate < actual * 2.0, "estimated {} for {} objects", estimate, actual);
+        }
+    }
+
+    fn sorted_ids(objects: &[Rc<RefCell<dyn QuadObject>>]) -> Vec<u32> {
+        let mut ids = ids(objects);
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn duplicate_and_enclosing_policies_answer_queries_alike() {
+        let scene = generate_scene(1500, 5, &TreeSurface::from_size(0, 0, 1000, 1000));
+        let build = |insert_policy: InsertPolicy| {
+            let config = TreeConfig { surface: TreeSurface::from_size(0, 0, 1000, 1000), insert_policy, ..TreeConfig::default() };
+            QuadTree::from_config(config, &scene)
+        };
+        let (duplicate, enclosing) = (build(InsertPolicy::Duplicate), build(InsertPolicy::Enclosing));
+
+        for query in [Rectangle::new(0, 0, 0, 1000, 1000), Rectangle::new(0, 240, 240, 30, 30), Rectangle::new(0, 499, 0, 2, 1000)] {
+            assert_eq!(sorted_ids(&duplicate.query_objects_in(&query)), sorted_ids(&enclosing.query_objects_in(&query)));
+        }
+        assert_eq!(sorted_ids(&duplicate.query_in_radius(500, 500, 120)), sorted_ids(&enclosing.query_in_radius(500, 500, 120)));
+        assert_eq!(sorted_ids(&duplicate.query_point(250, 750)), sorted_ids(&enclosing.query_point(250, 750)));
+        assert!(enclosing.object_count() < duplicate.object_count());
+    }
+
+    #[test]
+    fn morton_order_visits_quadrants_in_z_order() {
+        let mut tree = QuadTree::new(0, 0, 100, 100);
+        for (id, (x, y)) in [(90, 90), (10, 90), (90, 10), (60, 10), (10, 10)].into_iter().enumerate() {
+            tree.insert_object(shared(Boid::new(id as u32, x, y, 0.0))).unwrap();
+        }
+        // Top left, along the top row, then the bottom row left to right
+        assert_eq!(ids(&tree.objects_morton_order()), vec![4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn grow_to_fit_re_roots_around_the_old_tree() {
+        let mut tree = QuadTree::new(0, 0, 100, 100);
+        for id in 0..40 {
+            tree.insert_object(shared(Boid::new(id, (id as i32 % 8) * 12 + 4, (id as i32 / 8) * 20 + 4, 0.0))).unwrap();
+        }
+        let (nodes, deepest) = (tree.node_count(), tree.deepest_node());
+
+        tree.grow_to_fit(1000, 50);
+        let surface = tree.config().surface;
+        assert_eq!((surface.x0, surface.y0, surface.x1, surface.y1), (0, 0, 1615, 1615));
+        // Four doublings, each adding a root and three empty siblings around the untouched old tree
+        assert_eq!(tree.node_count(), nodes + 4 * 4);
+        assert_eq!(tree.deepest_node(), deepest + 4);
+
+        tree.insert_object(shared(Boid::new(40, 1000, 50, 0.0))).unwrap();
+        assert_eq!(ids(&tree.query_point(1000, 50)), vec![40]);
+        assert_eq!(tree.query_objects_in(&Rectangle::new(0, 0, 0, 100, 100)).len(), 40);
+
+        tree.grow_to_fit(-500, -10);
+        let surface = tree.config().surface;
+        assert_eq!((surface.x0, surface.y0, surface.x1, surface.y1), (-1616, -1616, 1615, 1615));
+        assert_eq!(tree.query_objects_in(&Rectangle::new(0, 0, 0, 1000, 1000)).len(), 41);
+    }
+
+    #[test]
+    fn query_outside_returns_only_objects_clear_of_the_region() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        let objects = [
+            shared(Rectangle::new(0, 120, 120, 10, 10)), // Inside
+            shared(Rectangle::new(1, 190, 150, 30, 10)), // Straddling the right edge
+            shared(Circle::new(2, 100, 100, 15)), // Straddling the top left corner
+            shared(Boid::new(3, 150, 150, 0.0)), // Inside
+            shared(Rectangle::new(4, 0, 0, 50, 50)), // Outside
+            shared(Boid::new(5, 300, 300, 0.0)), // Outside
+            shared(Circle::new(6, 260, 150, 20)), // Outside, right of the region
+        ];
+        for object in objects {
+            tree.insert_object(object).unwrap();
+        }
+        // Enough points on both sides for the tree to split
+        for id in 7..47 {
+            tree.insert_object(shared(Boid::new(id, 110 + (id as i32 % 8) * 10, 110 + (id as i32 / 8) * 10, 0.0))).unwrap();
+        }
+
+        let mut outside = ids(&tree.query_outside(&Rectangle::new(0, 100, 100, 100, 100)));
+        outside.sort_unstable();
+        assert_eq!(outside, vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn root_objects_empties_once_the_root_splits() {
+        let mut tree = QuadTree::builder().bounds(0, 0, 500, 500).capacity(4).build();
+        for id in 0..4 {
+            tree.insert_object(shared(Boid::new(id, 50 + id as i32 * 100, 50, 0.0))).unwrap();
+        }
+        assert_eq!(ids(&tree.root_objects()), vec![0, 1, 2, 3]);
+
+        tree.insert_object(shared(Boid::new(4, 450, 450, 0.0))).unwrap();
+        assert!(tree.root_objects().is_empty());
+    }
+
+    #[test]
+    fn overlap_counts_count_each_touching_neighbour() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        tree.insert_object(shared(Rectangle::new(0, 200, 200, 100, 100))).unwrap();
+        // Three around the central rectangle, far enough apart not to touch each other
+        tree.insert_object(shared(Circle::new(1, 200, 250, 20))).unwrap();
+        tree.insert_object(shared(Rectangle::new(2, 280, 180, 40, 40))).unwrap();
+        tree.insert_object(shared(Boid::new(3, 250, 300, 0.0))).unwrap();
+        tree.insert_object(shared(Boid::new(4, 50, 50, 0.0))).unwrap();
+
+        let counts = tree.overlap_counts();
+        assert_eq!(counts[&0], 3);
+        assert_eq!((counts[&1], counts[&2], counts[&3]), (1, 1, 1));
+        assert_eq!(counts[&4], 0);
+    }
+
+    #[test]
+    fn annulus_keeps_only_the_ring() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        for (id, distance) in [0, 10, 19, 20, 35, 50, 51, 80].into_iter().enumerate() {
+            tree.insert_object(shared(Boid::new(id as u32, 250 + distance, 250, 0.0))).unwrap();
+        }
+        let mut ring = ids(&tree.query_in_annulus(250, 250, 20, 50));
+        ring.sort_unstable();
+        assert_eq!(ring, vec![3, 4, 5]);
+
+        assert_eq!(tree.query_in_annulus(250, 250, 0, 19).len(), 3);
+        assert!(tree.query_in_annulus(250, 250, 50, 20).is_empty());
+    }
+
+    #[test]
+    fn remove_in_clears_a_corner_and_keeps_the_rest() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        for id in 0..100 {
+            tree.insert_object(shared(Boid::new(id, (id as i32 % 10) * 50 + 20, (id as i32 / 10) * 50 + 20, 0.0))).unwrap();
+        }
+        let corner = Rectangle::new(0, 0, 0, 100, 100);
+
+        let mut removed = ids(&tree.remove_in(&corner));
+        removed.sort_unstable();
+        assert_eq!(removed, vec![0, 1, 10, 11]);
+        assert!(tree.query_objects_in(&corner).is_empty());
+        assert_eq!(tree.object_count(), 96);
+        assert_eq!(ids(&tree.query_point(120, 20)), vec![2]);
+        assert_eq!(tree.query_objects_in(&Rectangle::new(0, 0, 0, 500, 500)).len(), 96);
+    }
+
+    #[test]
+    fn adjacency_widens_the_neighbour_search() {
+        // Four boids in the four leaves of a split root, the first one right at the corner of its leaf
+        let mut tree = QuadTree::builder().bounds(0, 0, 500, 500).capacity(2).build();
+        let boid = shared(Boid::new(0, 249, 249, 0.0));
+        tree.insert_object(Rc::clone(&boid)).unwrap();
+        tree.insert_object(shared(Boid::new(1, 252, 100, 0.0))).unwrap(); // Across the vertical split
+        tree.insert_object(shared(Boid::new(2, 252, 252, 0.0))).unwrap(); // Diagonal
+        tree.insert_object(shared(Boid::new(3, 100, 400, 0.0))).unwrap(); // Below, far from the first
+
+        let neighbours = |adjacency: Adjacency| {
+            let mut found = ids(&tree.query_neighbours_with_adjacency(&boid, adjacency));
+            found.sort_unstable();
+            found
+        };
+        assert!(neighbours(Adjacency::SameLeaf).is_empty());
+        assert_eq!(neighbours(Adjacency::FourWay), vec![1, 3]);
+        assert_eq!(neighbours(Adjacency::EightWay), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn merge_holds_the_union_of_both_ids() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        for id in 0..30 {
+            tree.insert_object(shared(Boid::new(id, 10 + id as i32 * 15, 100, 0.0))).unwrap();
+        }
+        // Ids 20 to 29 are in both scenes, the second one reaches past the first surface
+        let mut other = QuadTree::new(0, 0, 1000, 1000);
+        for id in 20..60 {
+            other.insert_object(shared(Boid::new(id, 10 + id as i32 * 15, 300, 0.0))).unwrap();
+        }
+
+        tree.merge(other);
+        assert_eq!(ids(&tree.objects_sorted_by_id()), (0..60).collect::<Vec<u32>>());
+        assert_eq!(tree.query_objects_in(&Rectangle::new(0, 0, 0, 1000, 1000)).len(), 60);
+    }
+
+    #[test]
+    fn objects_on_splits_returns_only_the_straddler() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        tree.insert_object(shared(Rectangle::new(0, 200, 200, 100, 100))).unwrap();
+        // Four in each quadrant, clear of the split lines
+        for id in 1..17 {
+            let (quadrant, index) = ((id - 1) / 4, (id - 1) % 4);
+            let (x, y) = ((quadrant % 2) * 300 + 50 + index * 30, (quadrant / 2) * 300 + 50);
+            tree.insert_object(shared(Boid::new(id as u32, x, y, 0.0))).unwrap();
+        }
+        assert!(tree.node_count() > 1);
+        assert_eq!(ids(&tree.objects_on_splits()), vec![0]);
+    }
+
+    #[test]
+    fn config_round_trips_through_from_config() {
+        let mut tree = QuadTree::builder().bounds(10, 10, 400, 300).capacity(3).max_depth(5).min_node_size(4).fanout(3)
+            .insert_policy(InsertPolicy::Enclosing).sorted_leaves(true).build();
+        let scene = generate_scene(200, 3, &TreeSurface::from_size(10, 10, 410, 310));
+        for object in scene.iter() {
+            tree.insert_object(Rc::clone(object)).unwrap();
+        }
+
+        let config = tree.config();
+        let rebuilt = QuadTree::from_config(config, &scene);
+        let restored = rebuilt.config();
+        assert!(restored.surface == config.surface);
+        assert_eq!((restored.capacity, restored.max_depth, restored.min_node_size, restored.fanout), (3, 5, 4, 3));
+        assert!(restored.insert_policy == InsertPolicy::Enclosing && restored.sorted_leaves);
+        assert_eq!(rebuilt.object_count(), tree.object_count());
+        assert_eq!(rebuilt.node_count(), tree.node_count());
+    }
+
+    #[test]
+    fn find_in_stops_at_the_first_match() {
+        let checks = Arc::new(AtomicUsize::new(0));
+        let tree = probe_grid(&checks);
+        let query = Rectangle::new(100, 90, 90, 60, 60);
+
+        let calls = Cell::new(0);
+        let found = tree.find_in(&query, |_| { calls.set(calls.get() + 1); true });
+        assert!(found.is_some());
+        // The first object of the first leaf visited matches, nothing after it is looked at
+        assert_eq!((checks.load(Ordering::Relaxed), calls.get()), (1, 1));
+
+        checks.store(0, Ordering::Relaxed);
+        assert_eq!(tree.query_objects_in(&query).len(), 100);
+        assert!(checks.load(Ordering::Relaxed) >= 100);
+    }
+
+    #[test]
+    fn splits_down_to_single_pixels_stay_valid() {
+        // 9 x 9 pixels, odd sizes on every level until the nodes are one pixel wide
+        let mut tree = QuadTree::builder().bounds(0, 0, 8, 8).capacity(1).min_node_size(1).max_depth(10).build();
+        for id in 0..81 {
+            tree.insert_object(shared(Boid::new(id, id as i32 % 9, id as i32 / 9, 0.0))).unwrap();
+        }
+        let nodes = tree.nodes_breadth_first();
+        assert!(nodes.iter().any(|node| node.surface.x0 == node.surface.x1 && node.surface.y0 == node.surface.y1));
+        assert!(tree.find_degenerate_nodes().is_empty());
+        for id in 0..81 {
+            assert_eq!(ids(&tree.query_point(id as i32 % 9, id as i32 / 9)), vec![id]);
+        }
+    }
+
+    #[test]
+    fn two_tight_groups_make_two_clusters() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        // Chains of boids 8 pixels apart, one in each far corner
+        for id in 0..20 {
+            let (x, y) = if id < 10 { (20 + id as i32 * 8, 30) } else { (380 + (id as i32 - 10) * 8, 450) };
+            tree.insert_object(shared(Boid::new(id, x, y, 0.0))).unwrap();
+        }
+        let mut clusters: Vec<Vec<u32>> = tree.clusters(10).iter().map(|cluster| {
+            let mut ids = ids(cluster);
+            ids.sort_unstable();
+            ids
+        }).collect();
+        clusters.sort();
+        assert_eq!(clusters, vec![(0..10).collect::<Vec<u32>>(), (10..20).collect()]);
+
+        // Links shorter than the spacing leave every boid on its own
+        assert_eq!(tree.clusters(7).len(), 20);
+    }
+
+    #[test]
+    fn occupancy_grid_marks_exactly_the_covered_cells() {
+        let mut tree = QuadTree::new(0, 0, 99, 99);
+        // Pixels 25 to 45 by 10 to 20, cells 2 to 4 in the first two rows of 10 pixel cells
+        tree.insert_object(shared(Rectangle::new(0, 25, 10, 20, 10))).unwrap();
+
+        let grid = tree.occupancy_grid(10);
+        assert_eq!((grid.len(), grid[0].len()), (10, 10));
+        for (row, cells) in grid.iter().enumerate() {
+            for (col, &occupied) in cells.iter().enumerate() {
+                assert_eq!(occupied, (1..=2).contains(&row) && (2..=4).contains(&col), "cell {} {}", row, col);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "positive cell size")]
+    fn occupancy_grid_rejects_a_zero_cell_size() {
+        QuadTree::new(0, 0, 99, 99).occupancy_grid(0);
+    }
+
+    #[test]
+    fn breadth_first_lists_every_level_before_the_next() {
+        let tree = scene_tree(300, 2);
+        let nodes = tree.nodes_breadth_first();
+        assert_eq!(nodes.len(), tree.node_count() as usize);
+        assert_eq!((nodes[0].depth, nodes[0].is_leaf), (1, false));
+        assert!(nodes.windows(2).all(|pair| pair[0].depth <= pair[1].depth));
+        assert_eq!(nodes.last().unwrap().depth, tree.deepest_node());
+        // The root's children come right after it, in row major order
+        assert_eq!(corners(&nodes[1..5].iter().map(|node| node.surface).collect::<Vec<_>>()), vec![(0, 0, 500, 500), (501, 0, 1000, 500), (0, 501, 500, 1000), (501, 501, 1000, 1000)]);
+    }
+
+    #[test]
+    fn sorted_leaves_answer_like_unsorted_ones() {
+        let scene = generate_scene(2000, 9, &TreeSurface::from_size(0, 0, 1000, 1000));
+        let build = |sorted_leaves: bool| {
+            let mut tree = QuadTree::builder().bounds(0, 0, 1000, 1000).capacity(64).sorted_leaves(sorted_leaves).build();
+            scene.iter().for_each(|object| tree.insert_object(Rc::clone(object)).unwrap());
+            tree
+        };
+        let (sorted, unsorted) = (build(true), build(false));
+
+        for (x, y, size) in [(0, 0, 1000), (100, 100, 37), (490, 480, 60), (999, 0, 1), (300, 700, 0)] {
+            let query = Rectangle::new(0, x, y, size, size);
+            assert_eq!(sorted_ids(&sorted.query_objects_in(&query)), sorted_ids(&unsorted.query_objects_in(&query)));
+            assert_eq!(sorted_ids(&sorted.query_surface(&query)), sorted_ids(&unsorted.query_surface(&query)));
+        }
+    }
+
+    #[test]
+    fn fragmentation_grows_with_removals() {
+        let mut tree = scene_tree(1000, 6);
+        assert!((tree.fragmentation() - 1.0).abs() < 0.1);
+
+        // Emptied leaves stay split until the tree is rebuilt
+        for id in 0..900 {
+            tree.remove_object(id);
+        }
+        assert!(tree.fragmentation() > 2.0);
+    }
+
+    #[test]
+    fn move_and_query_matches_a_move_then_a_query() {
+        let surface = TreeSurface::from_size(0, 0, 1000, 1000);
+        let (fused_scene, plain_scene) = (generate_scene(800, 12, &surface), generate_scene(800, 12, &surface));
+        let (mut fused, mut plain) = (QuadTree::from_config(TreeConfig { surface, ..TreeConfig::default() }, &fused_scene), QuadTree::from_config(TreeConfig { surface, ..TreeConfig::default() }, &plain_scene));
+
+        for (index, target) in [(3, (400, 400)), (10, (990, 15)), (200, (505, 498))] {
+            let neighbours = fused.move_and_query(&fused_scene[index], target, 60);
+
+            let (cx, cy) = plain_scene[index].as_ref().borrow().center();
+            plain_scene[index].as_ref().borrow_mut().translate(target.0 - cx, target.1 - cy);
+            plain.update_object(&plain_scene[index]);
+            let expected: Vec<_> = plain.query_in_radius(target.0, target.1, 60).into_iter().filter(|object| object.as_ref().borrow().get_id() != index as u32).collect();
+
+            assert_eq!(sorted_ids(&neighbours), sorted_ids(&expected));
+            assert!(ids(&fused.query_in_radius(target.0, target.1, 0)).contains(&(index as u32)));
+        }
+    }
+
+    #[test]
+    fn suggested_capacity_is_sensible_on_a_uniform_scene() {
+        let tree = scene_tree(2000, 8);
+        let suggested = tree.suggest_capacity();
+        assert!((4..=40).contains(&suggested), "suggested {}", suggested);
+
+        // An empty tree has nothing to go on and keeps its capacity
+        assert_eq!(QuadTree::with_capacity(0, 0, 100, 100, 7).suggest_capacity(), 7);
+    }
+
+    #[test]
+    fn quadrant_counts_split_filtered_matches_by_quadrant() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        let boids = [(50, 50), (100, 60), (200, 200), (300, 100), (300, 300), (450, 400), (480, 480)];
+        for (id, (x, y)) in boids.into_iter().enumerate() {
+            tree.insert_object(shared(Boid::new(id as u32, x, y, 0.0))).unwrap();
+        }
+        // Filtered out, and outside the query
+        tree.insert_object(shared(Circle::new(10, 60, 60, 5))).unwrap();
+        tree.insert_object(shared(Circle::new(11, 60, 400, 5))).unwrap();
+
+        let is_boid = |object: &Rc<RefCell<dyn QuadObject>>| object.as_ref().borrow().get_boid().is_some();
+        assert_eq!(tree.quadrant_counts_in(&Rectangle::new(0, 0, 0, 470, 470), is_boid), [3, 1, 0, 2]);
+        assert_eq!(tree.quadrant_counts_in(&Rectangle::new(0, 0, 0, 500, 500), |_| true), [4, 1, 1, 3]);
+    }
+
+    #[test]
+    fn each_out_of_bounds_policy_does_its_own_thing() {
+        let tree_with = |policy: OutOfBoundsPolicy| QuadTree::builder().bounds(0, 0, 500, 500).out_of_bounds(policy).build();
+        let escaped = || shared(Boid::new(0, 700, 100, 0.0));
+
+        let mut dropped = tree_with(OutOfBoundsPolicy::Drop);
+        assert_eq!(dropped.insert_object(escaped()), Ok(()));
+        assert_eq!(dropped.object_count(), 0);
+
+        let mut clamped = tree_with(OutOfBoundsPolicy::Clamp);
+        let object = escaped();
+        assert_eq!(clamped.insert_object(Rc::clone(&object)), Ok(()));
+        assert_eq!(object.as_ref().borrow().center(), (500, 100));
+        assert_eq!(ids(&clamped.query_point(500, 100)), vec![0]);
+
+        let mut grown = tree_with(OutOfBoundsPolicy::Grow);
+        assert_eq!(grown.insert_object(escaped()), Ok(()));
+        assert!(grown.config().surface.x1 >= 700);
+        assert_eq!(ids(&grown.query_point(700, 100)), vec![0]);
+
+        let mut strict = tree_with(OutOfBoundsPolicy::Error);
+        assert_eq!(strict.insert_object(escaped()), Err(OutOfBounds { id: 0, center: (700, 100) }));
+        assert_eq!(strict.object_count(), 0);
+    }
+
+    #[test]
+    fn path_to_follows_child_indices_down_to_the_leaf() {
+        let mut tree = QuadTree::builder().bounds(0, 0, 500, 500).capacity(1).build();
+        let (first, second, third) = (shared(Boid::new(0, 10, 10, 0.0)), shared(Boid::new(1, 100, 100, 0.0)), shared(Boid::new(2, 400, 300, 0.0)));
+        for object in [&first, &second, &third] {
+            tree.insert_object(Rc::clone(object)).unwrap();
+        }
+        // The first two share the top left twice before the third split puts them apart
+        assert_eq!(tree.path_to(&first), vec![vec![0, 0, 0]]);
+        assert_eq!(tree.path_to(&second), vec![vec![0, 0, 3]]);
+        assert_eq!(tree.path_to(&third), vec![vec![3]]);
+    }
+
+    #[test]
+    fn two_heavy_objects_split_a_node_below_its_count() {
+        let mut tree = QuadTree::builder().bounds(0, 0, 500, 500).capacity(4).build();
+        tree.insert_object(TestObject::area(0, 100, 100, 100, 100).with_weight(3).shared()).unwrap();
+        assert_eq!(tree.node_count(), 1);
+        tree.insert_object(TestObject::area(1, 400, 400, 400, 400).with_weight(3).shared()).unwrap();
+        assert_eq!(tree.node_count(), 5);
+        assert_eq!(ids(&tree.query_point(400, 400)), vec![1]);
+    }
+
+    #[test]
+    fn a_lone_heavy_object_does_not_split() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        tree.insert_object(TestObject::area(0, 10, 10, 490, 490).with_weight(50).shared()).unwrap();
+        assert_eq!((tree.node_count(), tree.object_count()), (1, 1));
+
+        for id in 1..21 {
+            tree.insert_object(shared(Boid::new(id, (id as i32 % 5) * 100 + 20, (id as i32 / 5) * 100 + 20, 0.0))).unwrap();
+        }
+        assert_eq!(tree.query_objects_in(&Rectangle::new(0, 0, 0, 500, 500)).len(), 21);
+    }
+
+    #[test]
+    fn tiny_query_on_a_coarse_tree_has_low_precision() {
+        let mut tree = QuadTree::builder().bounds(0, 0, 1000, 1000).capacity(64).build();
+        for object in generate_scene(300, 4, &TreeSurface::from_size(0, 0, 1000, 1000)) {
+            tree.insert_object(object).unwrap();
+        }
+        let precision = tree.query_precision(&Rectangle::new(0, 500, 500, 2, 2));
+        assert!(precision < 1.0, "precision {}", precision);
+        assert!(precision < 0.2);
+
+        // Covering everything, every scanned object is a hit unless it was scanned twice
+        assert!(tree.query_precision(&Rectangle::new(0, 0, 0, 1000, 1000)) > 0.9);
+    }
+
+    #[test]
+    fn query_precision_skips_what_content_bounds_prune() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        for id in 0..5 {
+            tree.insert_object(TestObject::point(id, 10 + id as i32 * 3, 10).shared()).unwrap();
+        }
+        // The root is touched but holds nothing near the query, query_objects_in tests none of its objects
+        assert_eq!(tree.query_precision(&Rectangle::new(0, 300, 300, 100, 100)), 1.0);
+        assert_eq!(tree.query_precision(&Rectangle::new(0, 0, 0, 14, 14)), 0.4);
+    }
+
+    #[test]
+    fn structurally_eq_compares_shape_and_leaf_ids() {
+        let surface = TreeSurface::from_size(0, 0, 1000, 1000);
+        let scene = generate_scene(500, 10, &surface);
+        let one_by_one = scene_tree(500, 10);
+        let from_config = QuadTree::from_config(one_by_one.config(), &scene);
+        assert!(one_by_one.structurally_eq(&from_config));
+
+        // The same scene with one object somewhere else
+        let mut moved = generate_scene(500, 10, &surface);
+        moved[0] = shared(Boid::new(0, 999, 999, 0.0));
+        assert!(!one_by_one.structurally_eq(&QuadTree::from_config(one_by_one.config(), &moved)));
+    }
+
+    #[test]
+    fn neighbour_cache_hits_until_the_tree_changes() {
+        let mut tree = scene_tree(300, 13);
+        let objects = tree.objects_sorted_by_id();
+        let mut cache = NeighbourCache::new();
+
+        let first = tree.neighbours_cached(&objects[0], &mut cache, 80);
+        assert_eq!((cache.hits(), cache.misses()), (0, 1));
+        let again = tree.neighbours_cached(&objects[0], &mut cache, 80);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+        assert_eq!(ids(&again), ids(&first));
+
+        // Another radius is its own entry, a mutation drops them all
+        tree.neighbours_cached(&objects[0], &mut cache, 40);
+        assert_eq!(cache.misses(), 2);
+        tree.remove_object(299);
+        tree.neighbours_cached(&objects[0], &mut cache, 80);
+        assert_eq!((cache.hits(), cache.misses()), (1, 3));
+    }
+
+    #[test]
+    fn visible_objects_leave_out_what_is_off_screen() {
+        let mut tree = QuadTree::new(0, 0, 2000, 2000);
+        tree.insert_object(shared(Boid::new(0, 100, 100, 0.0))).unwrap();
+        tree.insert_object(shared(Circle::new(1, 745, 300, 10))).unwrap(); // Partly on screen
+        tree.insert_object(shared(Rectangle::new(2, 1200, 100, 50, 50))).unwrap();
+        tree.insert_object(shared(Boid::new(3, 300, 900, 0.0))).unwrap();
+
+        let mut visible = ids(&tree.visible_objects(&Rectangle::new(0, 0, 0, 750, 550)));
+        visible.sort_unstable();
+        assert_eq!(visible, vec![0, 1]);
+    }
+
+    #[test]
+    fn content_bounds_prune_queries_away_from_the_objects() {
+        let checks = Arc::new(AtomicUsize::new(0));
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        // A handful in the top left corner of the unsplit root
+        for id in 0..5 {
+            tree.insert_object(TestObject::point(id, 10 + id as i32 * 3, 10).counting(&checks).shared()).unwrap();
+        }
+        assert_eq!(tree.node_count(), 1);
+        checks.store(0, Ordering::Relaxed);
+
+        assert!(tree.query_objects_in(&Rectangle::new(0, 300, 300, 100, 100)).is_empty());
+        assert_eq!(checks.load(Ordering::Relaxed), 0);
+        assert_eq!(tree.query_objects_in(&Rectangle::new(0, 0, 0, 20, 20)).len(), 4);
+        assert_eq!(checks.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn query_boids_in_returns_only_boids() {
+        let tree = scene_tree(300, 14);
+        let query = Rectangle::new(0, 200, 200, 500, 500);
+        let boids = tree.query_boids_in(&query);
+        assert!(!boids.is_empty());
+        assert!(boids.iter().all(|object| object.as_ref().borrow().get_boid().is_some()));
+        // generate_scene makes every third object a boid
+        let mut expected: Vec<u32> = tree.query_objects_in(&query).iter().map(|object| object.as_ref().borrow().get_id()).filter(|id| id % 3 == 0).collect();
+        expected.sort_unstable();
+        assert_eq!(sorted_ids(&boids), expected);
+    }
+
+    #[test]
+    fn node_limit_caps_the_node_count() {
+        let mut tree = QuadTree::builder().bounds(0, 0, 500, 500).capacity(2).max_nodes(9).build();
+        assert!(!tree.at_node_limit());
+        for id in 0..200 {
+            tree.insert_object(shared(Boid::new(id, (id as i32 % 20) * 25 + 5, (id as i32 / 20) * 50 + 5, 0.0))).unwrap();
+        }
+        assert!(tree.node_count() <= 9);
+        assert!(tree.at_node_limit());
+        assert_eq!(tree.query_objects_in(&Rectangle::new(0, 0, 0, 500, 500)).len(), 200);
+        assert_eq!(ids(&tree.query_point(30, 55)), vec![21]);
+    }
+
+    #[test]
+    fn manhattan_query_keeps_the_diamond_edges() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        let points = [(300, 250), (275, 275), (200, 250), (250, 200), (276, 275), (250, 199), (280, 280), (250, 250)];
+        for (id, (x, y)) in points.into_iter().enumerate() {
+            tree.insert_object(shared(Boid::new(id as u32, x, y, 0.0))).unwrap();
+        }
+        // On the diamond counts, the corners of its bounding box do not
+        assert_eq!(sorted_ids(&tree.query_manhattan(250, 250, 50)), vec![0, 1, 2, 3, 7]);
+        assert_eq!(sorted_ids(&tree.query_manhattan(250, 250, 0)), vec![7]);
+    }
+
+    #[test]
+    fn replace_swaps_a_circle_for_a_rectangle() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        tree.insert_object(shared(Circle::new(0, 100, 100, 10))).unwrap();
+        tree.insert_object(shared(Boid::new(1, 300, 300, 0.0))).unwrap();
+
+        assert!(tree.replace(0, shared(Rectangle::new(0, 200, 50, 40, 20))));
+        assert!(tree.query_point(100, 100).is_empty());
+        let found = tree.query_point(220, 60);
+        assert_eq!(ids(&found), vec![0]);
+        assert_eq!(found[0].as_ref().borrow().kind(), ObjectKind::Rectangle);
+        assert_eq!(tree.object_count(), 2);
+
+        // Nothing to replace, the new object goes in anyway
+        assert!(!tree.replace(5, shared(Boid::new(5, 50, 450, 0.0))));
+        assert_eq!(ids(&tree.query_point(50, 450)), vec![5]);
+    }
+
+    #[test]
+    fn hull_in_returns_the_corners_counter_clockwise() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        // A square with two points inside and one on an edge, plus one outside the query
+        let points = [(200, 200), (100, 100), (150, 100), (120, 170), (100, 200), (200, 100), (150, 150), (400, 400)];
+        for (id, (x, y)) in points.into_iter().enumerate() {
+            tree.insert_object(shared(Boid::new(id as u32, x, y, 0.0))).unwrap();
+        }
+        assert_eq!(tree.hull_in(&Rectangle::new(0, 50, 50, 200, 200)), vec![(100, 100), (200, 100), (200, 200), (100, 200)]);
+    }
+
+    #[test]
+    fn stream_counts_every_match_or_stops_after_k() {
+        let tree = scene_tree(600, 15);
+        let query = Rectangle::new(0, 100, 100, 600, 600);
+        let expected = tree.query_objects_in(&query).len();
+
+        let mut count = 0;
+        tree.query_objects_in_stream(&query, |_| { count += 1; true });
+        assert_eq!(count, expected);
+
+        let mut streamed = vec![];
+        tree.query_objects_in_stream(&query, |object| { streamed.push(object); streamed.len() < 5 });
+        assert_eq!(streamed.len(), 5);
+    }
+
+    #[test]
+    fn quad_and_alternating_splits_answer_queries_alike() {
+        let scene = generate_scene(1500, 16, &TreeSurface::from_size(0, 0, 1000, 1000));
+        let build = |split_mode: SplitMode| {
+            let mut tree = QuadTree::builder().bounds(0, 0, 1000, 1000).split_mode(split_mode).build();
+            scene.iter().for_each(|object| tree.insert_object(Rc::clone(object)).unwrap());
+            tree
+        };
+        let (quad, alternating) = (build(SplitMode::Quad), build(SplitMode::Alternating));
+        assert!(alternating.deepest_node() > quad.deepest_node());
+
+        for query in [Rectangle::new(0, 0, 0, 1000, 1000), Rectangle::new(0, 120, 610, 45, 80), Rectangle::new(0, 0, 499, 1000, 3)] {
+            assert_eq!(sorted_ids(&quad.query_objects_in(&query)), sorted_ids(&alternating.query_objects_in(&query)));
+        }
+        assert_eq!(sorted_ids(&quad.query_in_radius(700, 300, 90)), sorted_ids(&alternating.query_in_radius(700, 300, 90)));
+        assert_eq!(sorted_ids(&quad.query_point(500, 500)), sorted_ids(&alternating.query_point(500, 500)));
+    }
+
+    #[test]
+    fn root_quadrant_counts_follow_the_placement() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        let placement = [(3, (50, 50)), (2, (300, 50)), (0, (50, 300)), (6, (300, 300))];
+        let mut id = 0;
+        for (count, (x, y)) in placement {
+            for offset in 0..count {
+                tree.insert_object(shared(Boid::new(id, x + offset * 20, y, 0.0))).unwrap();
+                id += 1;
+            }
+        }
+        assert_eq!(tree.root_quadrant_object_counts(), [3, 2, 0, 6]);
+
+        // A straddler counts once in every quadrant it reaches
+        tree.insert_object(shared(Circle::new(id, 250, 100, 10))).unwrap();
+        assert_eq!(tree.root_quadrant_object_counts(), [4, 3, 0, 6]);
+    }
+
+    #[test]
+    fn enclosing_node_is_deep_in_a_corner_and_the_root_across_the_middle() {
+        let tree = scene_tree(2000, 17);
+        let (surface, depth) = tree.enclosing_node(&Rectangle::new(0, 2, 2, 3, 3));
+        assert!(depth >= 4);
+        assert_eq!((surface.x0, surface.y0), (0, 0));
+        assert!(surface.area() * 64 <= TreeSurface::from_size(0, 0, 1000, 1000).area());
+        // The corner leaf is the deepest node there, nothing below it could enclose the rectangle
+        assert_eq!(corners(&tree.leaves_in(&Rectangle::new(0, 2, 2, 3, 3))), corners(&[surface]));
+
+        let (surface, depth) = tree.enclosing_node(&Rectangle::new(0, 495, 495, 10, 10));
+        assert_eq!((corners(&[surface]), depth), (vec![(0, 0, 1000, 1000)], 1));
+    }
+
+    #[test]
+    fn classify_in_separates_contained_from_partial() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        tree.insert_object(shared(Circle::new(0, 200, 200, 20))).unwrap(); // Inside
+        tree.insert_object(shared(Circle::new(1, 300, 200, 20))).unwrap(); // Half outside the right edge
+        tree.insert_object(shared(Rectangle::new(2, 100, 100, 200, 200))).unwrap(); // The query itself
+        tree.insert_object(shared(Boid::new(3, 400, 400, 0.0))).unwrap(); // Outside
+
+        let (contained, partial) = tree.classify_in(&Rectangle::new(0, 100, 100, 200, 200));
+        assert_eq!(sorted_ids(&contained), vec![0, 2]);
+        assert_eq!(ids(&partial), vec![1]);
+    }
+
+    #[test]
+    fn collision_pairs_do_not_depend_on_insertion_order() {
+        let scene = generate_scene(600, 18, &TreeSurface::from_size(0, 0, 1000, 1000));
+        let pair_ids = |objects: &[Rc<RefCell<dyn QuadObject>>]| {
+            let tree = QuadTree::from_config(TreeConfig { surface: TreeSurface::from_size(0, 0, 1000, 1000), capacity: 4, ..TreeConfig::default() }, objects);
+            tree.collision_pairs().iter().map(|(a, b)| (a.as_ref().borrow().get_id(), b.as_ref().borrow().get_id())).collect::<Vec<(u32, u32)>>()
+        };
+        let forward = pair_ids(&scene);
+        let reversed = pair_ids(&scene.iter().rev().cloned().collect::<Vec<_>>());
+
+        assert!(!forward.is_empty());
+        assert_eq!(forward, reversed);
+        assert!(forward.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(forward.iter().all(|(a, b)| a < b));
+    }
+
+    #[test]
+    fn shrink_to_fit_prunes_nodes_and_leaf_storage() {
+        let mut tree = scene_tree(1000, 19);
+        let nodes = tree.node_count();
+        for id in 8..1000 {
+            tree.remove_object(id);
+        }
+        assert_eq!(tree.node_count(), nodes);
+        tree.shrink_to_fit();
+        assert_eq!(tree.node_count(), 1);
+        assert_eq!(sorted_ids(&tree.query_objects_in(&Rectangle::new(0, 0, 0, 1000, 1000))), (0..8).collect::<Vec<u32>>());
+
+        let mut wide = QuadTree::with_capacity(0, 0, 500, 500, 64);
+        for id in 0..64 {
+            wide.insert_object(shared(Boid::new(id, id as i32 * 7, 100, 0.0))).unwrap();
+        }
+        for id in 4..64 {
+            wide.remove_object(id);
+        }
+        let capacity = |tree: &QuadTree| tree.top_node.objects.as_ref().unwrap().capacity();
+        assert!(capacity(&wide) >= 64);
+        wide.shrink_to_fit();
+        assert_eq!(capacity(&wide), 4);
+    }
+
+    #[test]
+    fn query_in_by_area_puts_the_largest_first() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        tree.insert_object(shared(Circle::new(0, 100, 100, 5))).unwrap();
+        tree.insert_object(shared(Circle::new(1, 200, 100, 10))).unwrap();
+        tree.insert_object(shared(Rectangle::new(2, 150, 150, 100, 80))).unwrap();
+        tree.insert_object(shared(Circle::new(3, 300, 300, 5))).unwrap();
+        tree.insert_object(shared(Boid::new(4, 50, 50, 0.0))).unwrap();
+
+        // Equal circles keep id order, the boid covers a single pixel
+        assert_eq!(ids(&tree.query_in_by_area(&Rectangle::new(0, 0, 0, 500, 500))), vec![2, 1, 0, 3, 4]);
+    }
+
+    #[test]
+    fn node_data_survives_splits_until_clear() {
+        let mut tree = QuadTree::builder().bounds(0, 0, 500, 500).capacity(4).build_with_data::<f32>();
+        *tree.data_at_mut(10, 10).unwrap() = 5.0;
+        for id in 0..5 {
+            tree.insert_object(shared(Boid::new(id, 30 + id as i32 * 90, 30 + id as i32 * 90, 0.0))).unwrap();
+        }
+        assert_eq!(tree.node_count(), 5);
+
+        // Every child starts with the parent's value and then goes its own way
+        *tree.data_at_mut(400, 400).unwrap() += 1.0;
+        let mut values = vec![];
+        tree.for_each_node(|_, value| values.push(*value));
+        assert_eq!(values, vec![5.0, 5.0, 5.0, 5.0, 6.0]);
+        assert_eq!(tree.data_at(10, 10), Some(&5.0));
+
+        tree.clear();
+        assert_eq!(tree.data_at(10, 10), Some(&0.0));
+    }
+
+    #[test]
+    fn connected_follows_chains_of_links() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        // A chain 10 pixels apart from 0 to 5, then a gap of 40 before 6 and 7
+        let xs = [50, 60, 70, 80, 90, 100, 140, 150];
+        for (id, x) in xs.into_iter().enumerate() {
+            tree.insert_object(shared(Boid::new(id as u32, x, 200, 0.0))).unwrap();
+        }
+        assert!(tree.connected(0, 5, 10));
+        assert!(!tree.connected(0, 7, 10));
+        assert!(tree.connected(6, 7, 10));
+        // A longer link bridges the gap
+        assert!(tree.connected(0, 7, 40));
+    }
+
+    #[test]
+    fn query_iter_take_three_checks_only_three_objects() {
+        let checks = Arc::new(AtomicUsize::new(0));
+        let tree = probe_grid(&checks);
+        let query = Rectangle::new(100, 90, 90, 60, 60);
+
+        let first: Vec<_> = tree.query_iter(&query).take(3).collect();
+        assert_eq!(first.len(), 3);
+        assert_eq!(checks.load(Ordering::Relaxed), 3);
+        // The same objects, in the same order, as the collecting query
+        assert_eq!(ids(&first), ids(&tree.query_objects_in(&query)[..3]));
+    }
+
+    #[test]
+    fn resolve_collisions_calls_on_collide_on_both_objects() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        let first = Rc::new(RefCell::new(TestObject::area(0, 100, 100, 200, 200)));
+        let second = Rc::new(RefCell::new(TestObject::area(1, 150, 150, 250, 250)));
+        tree.insert_object(first.clone()).unwrap();
+        tree.insert_object(second.clone()).unwrap();
+        tree.insert_object(shared(Boid::new(2, 180, 180, 0.0))).unwrap();
+        tree.insert_object(shared(Boid::new(3, 400, 400, 0.0))).unwrap();
+
+        tree.resolve_collisions();
+        assert_eq!(first.borrow().collisions, vec![1, 2]);
+        assert_eq!(second.borrow().collisions, vec![0, 2]);
+    }
+
+    #[test]
+    fn min_node_size_stops_splits_around_a_cluster() {
+        let mut tree = QuadTree::builder().bounds(0, 0, 500, 500).capacity(1).max_depth(20).min_node_size(40).build();
+        for id in 0..50 {
+            tree.insert_object(shared(Boid::new(id, 300 + id as i32 % 7, 300 + id as i32 / 7, 0.0))).unwrap();
+        }
+        let nodes = tree.nodes_breadth_first();
+        assert!(nodes.len() > 1);
+        assert!(nodes.iter().all(|node| node.surface.x1 - node.surface.x0 + 1 >= 40 && node.surface.y1 - node.surface.y0 + 1 >= 40));
+        assert_eq!(tree.query_objects_in(&Rectangle::new(0, 290, 290, 20, 20)).len(), 50);
+    }
+
+    #[test]
+    fn normalized_corners_are_zero_and_one() {
+        let surface = TreeSurface::from_size(-100, 50, 300, 250);
+        assert_eq!(surface.normalize_point(-100, 50), (0.0, 0.0));
+        assert_eq!(surface.normalize_point(300, 250), (1.0, 1.0));
+        assert_eq!(surface.normalize_point(100, 150), (0.5, 0.5));
+
+        let tree = scene_tree(100, 20);
+        let normalized = tree.normalized_node_surfaces();
+        assert_eq!(normalized[0], (0.0, 0.0, 1.0, 1.0));
+        assert!(normalized.iter().all(|&(x0, y0, x1, y1)| (0.0..=1.0).contains(&x0) && (0.0..=1.0).contains(&y0) && x0 <= x1 && y0 <= y1 && x1 <= 1.0 && y1 <= 1.0));
+    }
+
+    #[test]
+    fn zero_size_query_acts_as_a_point() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        tree.insert_object(shared(Rectangle::new(0, 100, 100, 50, 50))).unwrap();
+        tree.insert_object(shared(Circle::new(1, 300, 300, 10))).unwrap();
+        tree.insert_object(shared(Rectangle::new(2, 400, 50, 0, 0))).unwrap();
+
+        // On the edge counts, like every other inclusive check
+        for (x, y, expected) in [(120, 130, vec![0]), (150, 150, vec![0]), (305, 300, vec![1]), (400, 50, vec![2]), (200, 200, vec![])] {
+            let click = Rectangle::new(9, x, y, 0, 0);
+            assert_eq!(ids(&tree.query_objects_in(&click)), expected);
+            assert_eq!(ids(&tree.query_surface(&click)), expected);
+        }
+    }
+
+    #[test]
+    fn scanline_order_goes_by_row_then_column() {
+        let mut tree = QuadTree::new(0, 0, 500, 500);
+        let points = [(300, 100), (50, 300), (100, 100), (400, 50), (200, 300), (450, 450)];
+        for (id, (x, y)) in points.into_iter().enumerate() {
+            tree.insert_object(shared(Boid::new(id as u32, x, y, 0.0))).unwrap();
+        }
+        assert_eq!(ids(&tree.query_scanline_order(&Rectangle::new(0, 0, 0, 420, 420))), vec![3, 2, 0, 1, 4]);
+    }
+
+    // A surface from i32::MAX / 2 up to i32::MAX itself, one pixel further would overflow
+    // Circles in the corners, boid positions are f32 and would round out of the surface this far out
+    fn huge_tree() -> QuadTree {
+        let mut tree = QuadTree::with_capacity(i32::MAX / 2, i32::MAX / 2, i32::MAX - i32::MAX / 2, i32::MAX - i32::MAX / 2, 1);
+        for (id, (x, y)) in huge_corners().into_iter().enumerate() {
+            tree.insert_object(shared(Circle::new(id as u32, x, y, 4))).unwrap();
+        }
+        tree
+    }
+    fn huge_corners() -> [(i32, i32); 4] {
+        let (near, far) = (i32::MAX / 2 + 10, i32::MAX - 10);
+        [(near, near), (far, near), (near, far), (far, far)]
+    }
+
+    #[test]
+    fn huge_surfaces_assign_corners_to_their_own_quadrants() {
+        let tree = huge_tree();
+        let middle = i32::MAX / 2 + i32::MAX / 4;
+        for (x, y) in huge_corners() {
+            let leaves = tree.leaves_in(&Rectangle::new(0, x, y, 0, 0));
+            assert_eq!(leaves.len(), 1);
+            // Each corner sits in the quadrant on its own side of the middle
+            assert_eq!((leaves[0].x0 < middle, leaves[0].y0 < middle), (x < middle, y < middle));
+            assert!(leaves[0].x0 <= x && x <= leaves[0].x1 && leaves[0].y0 <= y && y <= leaves[0].y1);
+        }
+        assert_eq!(tree.query_objects_in(&Rectangle::new(0, middle, middle, i32::MAX / 4, i32::MAX / 4)).len(), 1);
+    }
+
+    #[test]
+    fn huge_surfaces_do_not_overflow_grids_exports_or_neighbours() {
+        let tree = huge_tree();
+        let grid = tree.occupancy_grid(i32::MAX / 4);
+        assert_eq!((grid.len(), grid[0].len()), (3, 3));
+        assert!(grid[0][0] && grid[0][1] && grid[1][0] && grid[1][1]);
+
+        let svg = tree.to_svg(&tree.unique_objects());
+        assert!(svg.contains(&format!("viewBox=\"{0} {0} {1} {1}\"", i32::MAX / 2, i32::MAX - i32::MAX / 2)));
+
+        // The corner at the surface origin comes first, the far corner last
+        let order = ids(&tree.objects_morton_order());
+        assert_eq!((order[0], order[3]), (0, 3));
+
+        // The far corner leaf ends on i32::MAX, looking one pixel past it must not overflow
+        let far = tree.unique_objects().into_iter().find(|object| object.as_ref().borrow().get_id() == 3).unwrap();
+        assert_eq!(sorted_ids(&tree.query_neighbours_with_adjacency(&far, Adjacency::EightWay)), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn regions_at_returns_every_overlapping_label() {
+        let mut tree = QuadTree::new(0, 0, 1000, 1000);
+        tree.register_region(Rectangle::new(0, 100, 100, 300, 300), "spawn zone".to_string());
+        tree.register_region(Rectangle::new(0, 250, 250, 300, 300), "water".to_string());
+        tree.insert_object(shared(Boid::new(1, 275, 275, 0.0))).unwrap();
+
+        assert_eq!(tree.regions_at(275, 275), vec!["spawn zone", "water"]);
+        assert_eq!(tree.regions_at(150, 150), vec!["spawn zone"]);
+        assert!(tree.regions_at(900, 900).is_empty());
+        // Regions stay out of object queries
+        assert_eq!(ids(&tree.query_objects_in(&Rectangle::new(0, 0, 0, 1000, 1000))), vec![1]);
+    }
+
+    #[test]
+    fn equidistant_objects_go_to_the_lowest_id() {
+        // Inserted high id first, on either side of the query point and across the first split
+        let mut tree = QuadTree::with_capacity(0, 0, 1000, 1000, 1);
+        for (id, (x, y)) in [(7, (600, 500)), (3, (400, 500)), (5, (500, 600)), (9, (100, 100))] {
+            tree.insert_object(shared(Boid::new(id, x, y, 0.0))).unwrap();
+        }
+        for _ in 0..3 {
+            assert_eq!(tree.nearest(500, 500).unwrap().as_ref().borrow().get_id(), 3);
+        }
+        assert_eq!(ids(&tree.k_nearest(500, 500, 3)), vec![3, 5, 7]);
+        assert_eq!(ids(&tree.k_nearest(500, 500, 2)), vec![3, 5]);
+        assert_eq!(ids(&tree.k_nearest(500, 500, 10)), vec![3, 5, 7, 9]);
+        assert!(tree.k_nearest(500, 500, 0).is_empty());
+    }
+
+    #[test]
+    fn halving_capacity_lets_shallow_nodes_hold_more_than_deep_ones() {
+        // Five boids in the top left quarter, three in the bottom right
+        let positions = [(50, 50), (150, 100), (300, 200), (100, 350), (400, 400), (700, 700), (800, 900), (900, 600)];
+        let build = |scaling: CapacityScaling| {
+            let mut tree = QuadTree::builder().bounds(0, 0, 1000, 1000).capacity(8).capacity_scaling(scaling).build();
+            for (id, (x, y)) in positions.into_iter().enumerate() {
+                tree.insert_object(shared(Boid::new(id as u32, x, y, 0.0))).unwrap();
+            }
+            tree
+        };
+
+        let mut halving = build(CapacityScaling::Halving);
+        assert_eq!((halving.config().capacity_at_depth(1), halving.config().capacity_at_depth(2)), (8, 4));
+        // The root holds all eight
+        assert_eq!(halving.deepest_node(), 1);
+
+        // A ninth splits the root, the quarter with five is over its halved capacity and splits again
+        halving.insert_object(shared(Boid::new(8, 600, 100, 0.0))).unwrap();
+        assert_eq!(halving.deepest_node(), 3);
+        assert_eq!(halving.enclosing_node(&Rectangle::new(0, 700, 700, 200, 200)).1, 2);
+
+        let mut constant = build(CapacityScaling::Constant);
+        constant.insert_object(shared(Boid::new(8, 600, 100, 0.0))).unwrap();
+        assert_eq!(constant.deepest_node(), 2);
+    }
+
+    #[test]
+    fn build_reporting_lists_only_the_dropped_objects() {
+        let mut tree = QuadTree::with_capacity(0, 0, 1000, 1000, 2);
+        let objects = [
+            shared(Boid::new(0, 100, 100, 0.0)),
+            shared(Boid::new(1, 1500, 100, 0.0)),
+            shared(Circle::new(2, 500, 500, 20)),
+            shared(Rectangle::new(3, -300, -300, 100, 100)),
+            shared(Circle::new(4, 990, 990, 30)),
+            shared(Boid::new(5, 400, -20, 0.0)),
+        ];
+        assert_eq!(tree.build_reporting(&objects), vec![1, 3, 5]);
+        assert_eq!(sorted_ids(&tree.query_objects_in(&Rectangle::new(0, 0, 0, 1000, 1000))), vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn query_facing_keeps_boids_heading_at_the_target() {
+        let mut tree = QuadTree::new(0, 0, 1000, 1000);
+        // Facing 0 heads along +y, straight at the target below
+        tree.insert_object(shared(Boid::new(1, 500, 500, 0.0))).unwrap();
+        tree.insert_object(shared(Boid::new(2, 520, 500, PI))).unwrap();
+        tree.insert_object(shared(Circle::new(3, 480, 500, 5))).unwrap();
+        // Facing the target, but outside the region
+        tree.insert_object(shared(Boid::new(4, 100, 100, 0.0))).unwrap();
+
+        let facing = tree.query_facing((500, 800), &Rectangle::new(0, 400, 400, 200, 200), PI / 8.0);
+        assert_eq!(ids(&facing), vec![1]);
+    }
+
+    #[test]
+    fn rebuild_region_leaves_the_rest_of_the_tree_alone() {
+        let mut tree = QuadTree::with_capacity(0, 0, 1000, 1000, 2);
+        let elsewhere = [shared(Boid::new(10, 700, 100, 0.0)), shared(Boid::new(11, 200, 800, 0.0)), shared(Circle::new(12, 800, 800, 10))];
+        for object in elsewhere.iter() {
+            tree.insert_object(Rc::clone(object)).unwrap();
+        }
+        // Straddles the right edge of the rebuilt quadrant and is not handed back
+        tree.insert_object(shared(Circle::new(13, 495, 250, 30))).unwrap();
+        for id in 0..4 {
+            tree.insert_object(shared(Boid::new(id, 50 + id as i32 * 100, 100, 0.0))).unwrap();
+        }
+
+        let quadrant = Rectangle::new(0, 0, 0, 499, 499);
+        let moved = [shared(Boid::new(0, 300, 300, 0.0)), shared(Boid::new(2, 60, 450, 0.0)), shared(Boid::new(20, 250, 250, 0.0))];
+        tree.rebuild_region(&quadrant, &moved);
+
+        assert_eq!(sorted_ids(&tree.query_objects_in(&quadrant)), vec![0, 2, 13, 20]);
+        assert!(tree.query_objects_in(&Rectangle::new(0, 200, 300, 20, 20)).is_empty());
+        assert_eq!(ids(&tree.query_objects_in(&Rectangle::new(0, 290, 290, 20, 20))), vec![0]);
+        // Untouched objects are still the same instances in the same places
+        for object in elsewhere.iter() {
+            let (x, y) = object.as_ref().borrow().center();
+            let found = tree.query_objects_in(&Rectangle::new(0, x, y, 0, 0));
+            assert!(found.len() == 1 && Rc::ptr_eq(&found[0], object));
+        }
+        assert_eq!(ids(&tree.query_objects_in(&Rectangle::new(0, 520, 250, 0, 0))), vec![13]);
+    }
+
+    #[test]
+    fn capacity_hint_presizes_the_result() {
+        let tree = scene_tree(200, 4);
+        let query = Rectangle::new(0, 200, 200, 400, 400);
+        let hinted = tree.query_objects_in_with_capacity(&query, 100);
+        assert!(hinted.capacity() >= 100);
+        assert_eq!(ids(&hinted), ids(&tree.query_objects_in(&query)));
+    }
+
+    #[test]
+    fn query_swept_finds_a_boid_before_it_enters() {
+        let mut tree = QuadTree::with_capacity(0, 0, 1000, 1000, 2);
+        // Facing 0 moves one pixel along +y per update
+        tree.insert_object(shared(Boid::new(1, 500, 300, 0.0))).unwrap();
+        tree.insert_object(shared(Boid::new(2, 520, 310, PI))).unwrap();
+        tree.insert_object(shared(Boid::new(3, 480, 100, 0.0))).unwrap();
+        tree.insert_object(shared(Circle::new(4, 800, 800, 10))).unwrap();
+
+        let query = Rectangle::new(0, 450, 320, 100, 100);
+        assert!(tree.query_objects_in(&query).is_empty());
+        assert_eq!(ids(&tree.query_swept(&query, 30.0)), vec![1]);
+        assert!(tree.query_swept(&query, 10.0).is_empty());
+        // Looking further ahead, the boid further up arrives too
+        assert_eq!(sorted_ids(&tree.query_swept(&query, 250.0)), vec![1, 3]);
+    }
+
+    #[test]
+    fn objects_sorted_by_id_ignore_insertion_order() {
+        let objects: Vec<(u32, i32, i32)> = (0..60).map(|id| (id, (id as i32 * 37) % 1000, (id as i32 * 91) % 1000)).collect();
+        let build = |order: &[(u32, i32, i32)]| {
+            let mut tree = QuadTree::with_capacity(0, 0, 1000, 1000, 3);
+            for &(id, x, y) in order {
+                let object = if id % 3 == 0 { shared(Circle::new(id, x, y, 40)) } else { shared(Boid::new(id, x, y, 0.0)) };
+                tree.insert_object(object).unwrap();
+            }
+            tree
+        };
+        let forward = build(&objects);
+        let reversed = build(&objects.iter().rev().copied().collect::<Vec<_>>());
+
+        // Circles straddle splits and are stored more than once, but listed once
+        assert_eq!(ids(&forward.objects_sorted_by_id()), (0..60).collect::<Vec<u32>>());
+        assert_eq!(ids(&reversed.objects_sorted_by_id()), ids(&forward.objects_sorted_by_id()));
+    }
+
+    #[test]
+    fn every_shape_touching_the_far_edge_inserts() {
+        let tree_with = |dx: i32| {
+            let mut tree = QuadTree::with_capacity(0, 0, 1000, 1000, 1);
+            let surface = *tree.get_surface();
+            // A boid on x1, a rectangle starting on it and a circle whose left edge reaches it
+            tree.insert_object(shared(Boid::new(0, surface.x1 + dx, 500, 0.0))).unwrap();
+            tree.insert_object(shared(Rectangle::new(1, surface.x1 + dx, 300, 20, 20))).unwrap();
+            tree.insert_object(shared(Circle::new(2, surface.x1 + 10 + dx, 700, 10))).unwrap();
+            tree
+        };
+
+        let on_edge = tree_with(0);
+        assert_eq!(ids(&on_edge.objects_sorted_by_id()), vec![0, 1, 2]);
+        assert_eq!(sorted_ids(&on_edge.query_objects_in(&Rectangle::new(0, 1000, 0, 0, 1000))), vec![0, 1, 2]);
+        // One pixel further out none of them do
+        assert!(tree_with(1).objects_sorted_by_id().is_empty());
+    }
+
+    #[test]
+    fn matched_by_depth_fills_the_deep_bin_for_a_cluster() {
+        let mut tree = QuadTree::with_capacity(0, 0, 1000, 1000, 2);
+        for id in 0..16 {
+            tree.insert_object(shared(Boid::new(id, 100 + (id % 4) as i32 * 5, 100 + (id / 4) as i32 * 5, 0.0))).unwrap();
+        }
+        for (id, (x, y)) in [(16, (700, 200)), (17, (300, 800))] {
+            tree.insert_object(shared(Boid::new(id, x, y, 0.0))).unwrap();
+        }
+
+        let counts = tree.matched_by_depth_in(&Rectangle::new(0, 0, 0, 1000, 1000));
+        assert_eq!(counts.len() as i32, tree.deepest_node() + 1);
+        assert_eq!(counts.iter().sum::<usize>(), 18);
+        // The two loners sit in leaves right under the root, the cluster only in the deepest ones
+        assert_eq!(counts[2], 2);
+        assert!(tree.deepest_node() > 4 && *counts.last().unwrap() > 0);
+        assert_eq!(counts[..2], [0, 0]);
+    }
+
+    #[test]
+    fn every_query_method_adds_to_the_query_stats() {
+        let tree = scene_tree(500, 8);
+        let stats = tree.query_stats();
+        let query = Rectangle::new(0, 200, 200, 300, 300);
+
+        // One of each, with the number of objects it handed back
+        let mut returned = vec![
+            tree.query_objects_in(&query).len(),
+            tree.query_in_radius(500, 500, 150).len(),
+            tree.query_point(500, 500).len(),
+            tree.query_surface(&query).len(),
+            tree.query_contained_in(&query).len(),
+            tree.query_outside(&query).len(),
+            tree.query_manhattan(500, 500, 150).len(),
+            tree.nearest_with_distance(500, 500).iter().count(),
+            tree.k_nearest(500, 500, 5).len(),
+            tree.query_objects_in_limited(&query, 7).len(),
+            tree.find_in(&query, |_| true).iter().count(),
+            tree.query_objects_in_with_depth(&query).len(),
+            tree.query_swept(&query, 20.0).len(),
+        ];
+        let mut streamed = 0;
+        tree.query_objects_in_stream(&query, |_| { streamed += 1; streamed < 4 });
+        returned.push(streamed);
+        returned.push(tree.query_iter(&query).take(3).count());
+        let object = Rc::clone(&tree.unique_objects()[0]);
+        returned.push(tree.query_neighbours_and_condition(&object, Some(10)).len());
+        returned.push(tree.leaves_in(&query).len());
+        let mut joined = 0;
+        tree.join(&tree, |_, _| joined += 1);
+        returned.push(joined);
+        let pairs = tree.collision_pairs().len();
+        returned.push(pairs);
+        tree.resolve_collisions();
+        returned.push(pairs);
+        // Precision finds what query_objects_in returned
+        tree.query_precision(&query);
+        returned.push(returned[0]);
+
+        assert_eq!(stats.queries(), returned.len());
+        assert_eq!(stats.results(), returned.iter().sum::<usize>());
+        assert!(stats.nodes_visited() >= returned.len());
+        assert_eq!(stats.mean_results(), stats.results() as f32 / returned.len() as f32);
+
+        let mut tree = tree;
+        tree.reset_query_stats();
+        assert_eq!((tree.query_stats().queries(), tree.query_stats().results(), tree.query_stats().nodes_visited()), (0, 0, 0));
+    }
+
+    #[test]
+    fn remove_many_drops_half_and_collapses_the_emptied_nodes() {
+        let mut tree = scene_tree(400, 11);
+        let objects = tree.objects_sorted_by_id();
+        let nodes_before = tree.node_count();
+
+        // Every object reaching into the left half of the surface, about half of them
+        let left: Vec<u32> = objects.iter().filter(|object| object.as_ref().borrow().bounds().x0 < 500).map(|object| object.as_ref().borrow().get_id()).collect();
+        assert!(left.len() > 150 && left.len() < 250);
+        tree.remove_many(&left);
+
+        let rest: Vec<u32> = ids(&objects).into_iter().filter(|id| !left.contains(id)).collect();
+        assert_eq!(ids(&tree.objects_sorted_by_id()), rest);
+        assert_eq!(sorted_ids(&tree.query_objects_in(&Rectangle::new(0, 0, 0, 1000, 1000))), rest);
+        assert!(tree.query_objects_in(&Rectangle::new(0, 0, 0, 400, 1000)).is_empty());
+        // The emptied left quadrants collapsed back into two empty leaves
+        assert!(tree.node_count() < nodes_before);
+        assert_eq!(tree.leaves_in(&Rectangle::new(0, 0, 0, 400, 1000)).len(), 2);
+    }
+
+    #[test]
+    fn max_object_fanout_counts_the_leaves_a_spanning_rectangle_touches() {
+        let mut tree = QuadTree::with_capacity(0, 0, 1000, 1000, 2);
+        for (id, (x, y)) in [(100, 100), (150, 120), (900, 100), (880, 150), (100, 900), (120, 850), (900, 900), (880, 880)].into_iter().enumerate() {
+            tree.insert_object(shared(Boid::new(id as u32, x, y, 0.0))).unwrap();
+        }
+        assert_eq!(tree.max_object_fanout(), 1);
+
+        // Counted after inserting, the rectangle pushes the corner leaves over capacity and splits them further
+        let spanning = Rectangle::new(20, 50, 50, 900, 900);
+        tree.insert_object(shared(spanning)).unwrap();
+        let touched = tree.leaves_in(&Rectangle::new(0, 50, 50, 900, 900)).len();
+        assert!(touched > 4);
+        assert_eq!(tree.max_object_fanout(), touched);
+    }
+
+    #[test]
+    fn hulls_spanning_the_whole_i32_range_do_not_overflow() {
+        let (low, high) = (i32::MIN + 10, i32::MAX - 10);
+        let hull = convex_hull(vec![(low, low), (high, low), (0, 0), (high, high), (low, high)]);
+        assert_eq!(hull, vec![(low, low), (high, low), (high, high), (low, high)]);
+    }
+
+    #[test]
+    fn bounds_past_the_end_of_the_i32_range_saturate() {
+        let tree = QuadTree::new(i32::MAX / 2, i32::MAX / 2, i32::MAX, i32::MAX);
+        assert!(*tree.get_surface() == TreeSurface::from_size(i32::MAX / 2, i32::MAX / 2, i32::MAX, i32::MAX));
+        assert!(TreeSurface::from_origin(-10, i32::MIN + 5, i32::MIN, -20) == TreeSurface::from_size(-10, i32::MIN + 5, i32::MIN, i32::MIN));
+    }
+}
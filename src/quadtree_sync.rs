@@ -0,0 +1,346 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::thread;
+use crate::quad_objects::{QuadObject, Rectangle};
+use crate::quadtree::{assign_object_to_cells, point_distance, OutOfBounds, OutOfBoundsPolicy, TreeConfig, TreeSurface};
+
+// Shared between threads, an object is read only while it sits in the tree
+pub type SyncObject = Arc<dyn QuadObject + Send + Sync>;
+
+fn dedup_by_id(objects: Vec<SyncObject>) -> Vec<SyncObject> {
+    let mut seen = HashSet::new();
+    objects.into_iter().filter(|object| seen.insert(object.get_id())).collect()
+}
+
+// --------------------
+// QuadTreeSync
+// --------------------
+// Same layout as QuadTree but storing Arc, so a built tree can be queried from several threads at once
+// Nodes split by the same rules as QuadTree, weights, capacity scaling, split mode, max depth, min node size and max nodes
+// Objects are shared read only, so some of the config is not supported:
+// - straddling objects are always duplicated, the insert policy is not used
+// - only the Drop and Error out of bounds policies, with_config panics on Clamp and Grow
+// - sorted_leaves is ignored, leaves are scanned in full
+pub struct QuadTreeSync {
+    top_node: SyncNode,
+    config: TreeConfig,
+}
+
+impl QuadTreeSync {
+    pub fn new(x0: i32, y0: i32, width: i32, height: i32) -> QuadTreeSync {
+        QuadTreeSync::with_config(x0, y0, width, height, TreeConfig::default())
+    }
+    pub fn with_config(x0: i32, y0: i32, width: i32, height: i32, config: TreeConfig) -> QuadTreeSync {
+        assert!(matches!(config.out_of_bounds, OutOfBoundsPolicy::Drop | OutOfBoundsPolicy::Error), "QuadTreeSync can not move shared objects, use the Drop or Error out of bounds policy");
+        let surface = TreeSurface::from_origin(x0, y0, width, height);
+        QuadTreeSync { top_node: SyncNode::new(1, surface), config: TreeConfig { surface, ..config } }
+    }
+
+    // Splits the root up front and fills each of its children on its own thread
+    // Under OutOfBoundsPolicy::Error the first object outside the surface fails the whole build
+    pub fn build_parallel(x0: i32, y0: i32, width: i32, height: i32, config: TreeConfig, objects: Vec<SyncObject>) -> Result<QuadTreeSync, OutOfBounds> {
+        let mut tree = QuadTreeSync::with_config(x0, y0, width, height, config);
+        let mut inside = Vec::with_capacity(objects.len());
+        for object in objects {
+            if tree.check_bounds(object.as_ref())? { inside.push(object); }
+        }
+
+        // The same guards as a serial insert, so a root that would stay a leaf is not split here either
+        // A node budget is spent in insertion order, which threads filling leaves side by side can not follow
+        let (depth, surface) = (tree.top_node.depth, tree.top_node.surface);
+        let over_capacity = inside.split_last().is_some_and(|(last, rest)| config.over_capacity(depth, rest.iter().map(|object| object.weight()), last.weight()));
+        if !over_capacity || config.max_nodes.is_some() || !config.can_split(depth, &surface, usize::MAX) {
+            let mut nodes_left = tree.nodes_left();
+            inside.into_iter().for_each(|object| tree.top_node.insert_object(object, &config, &mut nodes_left));
+            return Ok(tree);
+        }
+
+        let (columns, rows) = config.split_grid(depth);
+        let mut cells: Vec<Vec<SyncObject>> = vec![Vec::new(); columns * rows];
+        for object in inside {
+            for index in assign_object_to_cells(&surface, depth, (columns, rows), object.as_ref()) {
+                cells[index].push(Arc::clone(&object));
+            }
+        }
+
+        let mut leaves = tree.top_node.grid_children(&config);
+        thread::scope(|scope| {
+            for (leaf, cell) in leaves.iter_mut().zip(cells) {
+                scope.spawn(move || {
+                    let mut nodes_left = usize::MAX;
+                    cell.into_iter().for_each(|object| leaf.insert_object(object, &config, &mut nodes_left));
+                });
+            }
+        });
+        tree.top_node.objects = None;
+        tree.top_node.leaves = leaves;
+        Ok(tree)
+    }
+
+    // Objects outside the surface are dropped, or returned as Err under OutOfBoundsPolicy::Error
+    pub fn insert_object(&mut self, object: SyncObject) -> Result<(), OutOfBounds> {
+        if !self.check_bounds(object.as_ref())? { return Ok(()); }
+        let mut nodes_left = self.nodes_left();
+        self.top_node.insert_object(object, &self.config, &mut nodes_left);
+        Ok(())
+    }
+
+    // Whether the object overlaps the surface, the out of bounds policy decides between false and Err otherwise
+    fn check_bounds(&self, object: &(dyn QuadObject + Send + Sync)) -> Result<bool, OutOfBounds> {
+        if object.is_overlap(&self.config.surface) { return Ok(true); }
+        match self.config.out_of_bounds {
+            OutOfBoundsPolicy::Error => Err(OutOfBounds { id: object.get_id(), center: object.center() }),
+            _ => Ok(false),
+        }
+    }
+
+    // Nodes that may still be created before reaching max_nodes
+    fn nodes_left(&self) -> usize {
+        match self.config.max_nodes {
+            Some(max_nodes) => max_nodes.saturating_sub(self.top_node.node_count()),
+            None => usize::MAX,
+        }
+    }
+
+    pub fn query_objects_in(&self, query: &Rectangle) -> Vec<SyncObject> {
+        let mut query_result = vec![];
+        self.top_node.query_overlapping(&query.to_tree_surface(), &mut query_result);
+        dedup_by_id(query_result)
+    }
+
+    pub fn query_in_radius(&self, cx: i32, cy: i32, radius: i32) -> Vec<SyncObject> {
+        let mut query_result = vec![];
+        self.top_node.query_by_radius(cx, cy, radius as f32, &mut query_result);
+        dedup_by_id(query_result)
+    }
+
+    pub fn object_count(&self) -> usize {
+        let mut objects = vec![];
+        self.top_node.collect_objects(&mut objects);
+        dedup_by_id(objects).len()
+    }
+}
+
+// --------------------
+// SyncNode
+// --------------------
+struct SyncNode {
+    depth: i32,
+    surface: TreeSurface,
+    objects: Option<Vec<SyncObject>>, // Some while this node is a leaf
+    leaves: Vec<SyncNode>,
+}
+
+impl SyncNode {
+    fn new(depth: i32, surface: TreeSurface) -> SyncNode {
+        SyncNode { depth, surface, objects: Some(Vec::new()), leaves: Vec::new() }
+    }
+
+    // Children laid out like the children of a TreeNode at the same depth
+    fn grid_children(&self, config: &TreeConfig) -> Vec<SyncNode> {
+        let (columns, rows) = config.split_grid(self.depth);
+        let (xs, ys) = self.surface.cell_bounds_at_depth(self.depth, columns, rows);
+        let mut children = Vec::with_capacity(columns * rows);
+        for row in 0..rows {
+            for col in 0..columns {
+                children.push(SyncNode::new(self.depth + 1, TreeSurface::from_size(xs[col], ys[row], xs[col + 1] - 1, ys[row + 1] - 1)));
+            }
+        }
+        children
+    }
+
+    fn node_count(&self) -> usize {
+        1 + self.leaves.iter().map(|leaf| leaf.node_count()).sum::<usize>()
+    }
+
+    fn insert_object(&mut self, object: SyncObject, config: &TreeConfig, nodes_left: &mut usize) {
+        if self.objects.is_some() {
+            let objects = self.objects.as_mut().unwrap();
+            let over_capacity = config.over_capacity(self.depth, objects.iter().map(|object| object.weight()), object.weight());
+            if !over_capacity || !config.can_split(self.depth, &self.surface, *nodes_left) {
+                objects.push(object);
+                return;
+            }
+            // Split, then route the old objects and the new one through the leaves
+            let mut objects = self.objects.take().unwrap();
+            objects.push(object);
+            self.leaves = self.grid_children(config);
+            *nodes_left -= self.leaves.len();
+            for object in objects {
+                self.insert_into_leaves(object, config, nodes_left);
+            }
+        } else {
+            self.insert_into_leaves(object, config, nodes_left);
+        }
+    }
+
+    fn insert_into_leaves(&mut self, object: SyncObject, config: &TreeConfig, nodes_left: &mut usize) {
+        for index in assign_object_to_cells(&self.surface, self.depth, config.split_grid(self.depth), object.as_ref()) {
+            self.leaves[index].insert_object(Arc::clone(&object), config, nodes_left);
+        }
+    }
+
+    fn collect_objects(&self, objects: &mut Vec<SyncObject>) {
+        if let Some(leaf_objects) = &self.objects { objects.extend(leaf_objects.iter().cloned()); }
+        self.leaves.iter().for_each(|leaf| leaf.collect_objects(objects));
+    }
+
+    fn query_overlapping(&self, query_surface: &TreeSurface, query_result: &mut Vec<SyncObject>) {
+        if !query_surface.intersects(&self.surface) { return; }
+
+        if let Some(objects) = &self.objects {
+            for object in objects.iter() {
+                if object.is_overlap(query_surface) { query_result.push(Arc::clone(object)) }
+            }
+        }
+        for leaf in self.leaves.iter() {
+            leaf.query_overlapping(query_surface, query_result);
+        }
+    }
+
+    fn query_by_radius(&self, cx: i32, cy: i32, radius: f32, query_result: &mut Vec<SyncObject>) {
+        if self.surface.distance_to(cx, cy) > radius { return; }
+
+        if let Some(objects) = &self.objects {
+            for object in objects.iter() {
+                if point_distance((cx, cy), object.center()) <= radius { query_result.push(Arc::clone(object)) }
+            }
+        }
+        for leaf in self.leaves.iter() {
+            leaf.query_by_radius(cx, cy, radius, query_result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+    use crate::quad_objects::{Boid, Circle, Rectangle};
+    use crate::quadtree::{OutOfBoundsPolicy, QuadTree, SplitMode, TreeConfig, TreeSurface};
+    use crate::test_object::TestObject;
+    use super::{QuadTreeSync, SyncObject};
+
+    // A 20 x 20 grid of boids and circles, the circles straddle some of the splits
+    fn scene() -> Vec<SyncObject> {
+        (0..400u32).map(|id| {
+            let (x, y) = ((id % 20) as i32 * 25 + 5, (id / 20) as i32 * 25 + 5);
+            let object: SyncObject = if id % 2 == 0 { Arc::new(Boid::new(id, x, y, 0.0)) } else { Arc::new(Circle::new(id, x, y, 8)) };
+            object
+        }).collect()
+    }
+
+    #[test]
+    fn build_parallel_matches_a_serial_build() {
+        let mut serial = QuadTreeSync::new(0, 0, 500, 500);
+        scene().into_iter().for_each(|object| serial.insert_object(object).unwrap());
+        let parallel = QuadTreeSync::build_parallel(0, 0, 500, 500, TreeConfig::default(), scene()).unwrap();
+
+        assert_eq!(parallel.object_count(), serial.object_count());
+        assert_eq!(parallel.object_count(), 400);
+        let ids = |tree: &QuadTreeSync| tree.query_objects_in(&Rectangle::new(0, 100, 100, 150, 150)).iter().map(|object| object.get_id()).collect::<HashSet<u32>>();
+        assert_eq!(ids(&parallel), ids(&serial));
+    }
+
+    #[test]
+    fn threads_query_one_tree_concurrently() {
+        let mut tree = QuadTreeSync::new(0, 0, 500, 500);
+        scene().into_iter().for_each(|object| tree.insert_object(object).unwrap());
+        let tree = Arc::new(tree);
+
+        let handles: Vec<_> = (0..4).map(|quarter| {
+            let tree = Arc::clone(&tree);
+            thread::spawn(move || tree.query_objects_in(&Rectangle::new(0, (quarter % 2) * 250, (quarter / 2) * 250, 249, 249)).len())
+        }).collect();
+        let counts: Vec<usize> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+
+        // Every thread sees a quarter of the grid, circles near the splits show up in two of them
+        assert!(counts.iter().all(|&count| count >= 100));
+        assert_eq!(tree.query_in_radius(250, 250, 1000).len(), 400);
+    }
+
+    #[test]
+    fn build_parallel_follows_a_custom_config() {
+        let ids = |tree: &QuadTreeSync| tree.query_objects_in(&Rectangle::new(0, 60, 60, 200, 120)).iter().map(|object| object.get_id()).collect::<HashSet<u32>>();
+        let shallow = TreeConfig { max_depth: 1, ..TreeConfig::default() };
+        let tuned = TreeConfig { capacity: 3, fanout: 3, max_depth: 4, ..TreeConfig::default() };
+        for config in [shallow, tuned] {
+            let mut serial = QuadTreeSync::with_config(0, 0, 500, 500, config);
+            scene().into_iter().for_each(|object| serial.insert_object(object).unwrap());
+            let parallel = QuadTreeSync::build_parallel(0, 0, 500, 500, config, scene()).unwrap();
+
+            assert_eq!(parallel.top_node.leaves.len(), serial.top_node.leaves.len());
+            assert_eq!(parallel.object_count(), serial.object_count());
+            assert_eq!(ids(&parallel), ids(&serial));
+        }
+    }
+
+    // A 10 x 10 grid of points weighing 1 to 3, with a few areas straddling the middle splits
+    fn weighted_scene() -> Vec<TestObject> {
+        let mut objects: Vec<TestObject> = (0..100u32).map(|id| TestObject::point(id, (id % 10) as i32 * 50 + 10, (id / 10) as i32 * 50 + 10).with_weight(id % 3 + 1)).collect();
+        objects.extend((100..104u32).map(|id| TestObject::area(id, 240, id as i32 * 20 - 1800, 260, id as i32 * 20 - 1790)));
+        objects
+    }
+
+    // Node counts of a QuadTree, a serially filled QuadTreeSync and a build_parallel one over the same objects
+    fn node_counts(config: TreeConfig, objects: impl Fn() -> Vec<TestObject>) -> (usize, usize, usize) {
+        let config = TreeConfig { surface: TreeSurface::from_size(0, 0, 500, 500), ..config };
+        let shared: Vec<_> = objects().into_iter().map(|object| object.shared()).collect();
+        let tree = QuadTree::from_config(config, &shared);
+
+        let mut serial = QuadTreeSync::with_config(0, 0, 500, 500, config);
+        objects().into_iter().for_each(|object| serial.insert_object(Arc::new(object)).unwrap());
+        let sync_objects = objects().into_iter().map(|object| Arc::new(object) as SyncObject).collect();
+        let parallel = QuadTreeSync::build_parallel(0, 0, 500, 500, config, sync_objects).unwrap();
+        assert_eq!(parallel.object_count(), serial.object_count());
+        (tree.node_count() as usize, serial.top_node.node_count(), parallel.top_node.node_count())
+    }
+
+    #[test]
+    fn sync_trees_split_like_a_quadtree() {
+        let alternating = TreeConfig { capacity: 4, split_mode: SplitMode::Alternating, ..TreeConfig::default() };
+        let weighted = TreeConfig { capacity: 6, ..TreeConfig::default() };
+        let budget = TreeConfig { capacity: 2, max_nodes: Some(9), ..TreeConfig::default() };
+        for config in [alternating, weighted, budget] {
+            let (tree, serial, parallel) = node_counts(config, weighted_scene);
+            assert!(tree > 1);
+            assert_eq!((serial, parallel), (tree, tree));
+        }
+        assert!(node_counts(budget, weighted_scene).0 <= 9);
+
+        // A lone heavy object stays in the root
+        let lone = || vec![TestObject::area(0, 10, 10, 490, 490).with_weight(50)];
+        assert_eq!(node_counts(TreeConfig::default(), lone), (1, 1, 1));
+    }
+
+    #[test]
+    fn sync_trees_follow_the_out_of_bounds_policy() {
+        let outside = || vec![TestObject::point(0, 100, 100), TestObject::point(1, 900, 900)];
+        let dropping = TreeConfig::default();
+        let mut tree = QuadTreeSync::with_config(0, 0, 500, 500, dropping);
+        outside().into_iter().for_each(|object| tree.insert_object(Arc::new(object)).unwrap());
+        assert_eq!(tree.object_count(), 1);
+
+        let failing = TreeConfig { out_of_bounds: OutOfBoundsPolicy::Error, ..TreeConfig::default() };
+        let mut tree = QuadTreeSync::with_config(0, 0, 500, 500, failing);
+        assert!(tree.insert_object(Arc::new(TestObject::point(0, 100, 100))).is_ok());
+        assert_eq!(tree.insert_object(Arc::new(TestObject::point(1, 900, 900))).unwrap_err().id, 1);
+        let objects = outside().into_iter().map(|object| Arc::new(object) as SyncObject).collect();
+        assert!(QuadTreeSync::build_parallel(0, 0, 500, 500, failing, objects).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn sync_trees_refuse_to_clamp() {
+        QuadTreeSync::with_config(0, 0, 500, 500, TreeConfig { out_of_bounds: OutOfBoundsPolicy::Clamp, ..TreeConfig::default() });
+    }
+
+    #[test]
+    fn sync_bounds_past_the_end_of_the_i32_range_saturate() {
+        let mut tree = QuadTreeSync::new(i32::MAX / 2, 0, i32::MAX, 500);
+        assert!(tree.config.surface == TreeSurface::from_size(i32::MAX / 2, 0, i32::MAX, 500));
+        tree.insert_object(Arc::new(TestObject::point(0, i32::MAX - 10, 10))).unwrap();
+        assert_eq!(tree.object_count(), 1);
+    }
+}
@@ -0,0 +1,89 @@
+use std::cell::RefCell;
+use std::f32::consts::PI;
+use std::rc::Rc;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::quad_objects::{Boid, Circle, QuadObject, Rectangle};
+use crate::quadtree::TreeSurface;
+
+// --------------------
+// Scene generation
+// --------------------
+// A reproducible mix of boids, circles and rectangles inside the surface, ids 0..count
+// The same seed always gives the same scene, so benchmarks compare like with like
+pub fn generate_scene(count: usize, seed: u64, surface: &TreeSurface) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut scene: Vec<Rc<RefCell<dyn QuadObject>>> = Vec::with_capacity(count);
+
+    for id in 0..count as u32 {
+        match id % 3 {
+            0 => {
+                let (x, y) = (rng.gen_range(surface.x0..=surface.x1), rng.gen_range(surface.y0..=surface.y1));
+                scene.push(Rc::new(RefCell::new(Boid::new(id, x, y, rng.gen_range(0.0..6.0)))));
+            },
+            1 => {
+                // Keep the whole circle inside, small surfaces get small circles
+                let r = rng.gen_range(2..=10).min((surface.x1 - surface.x0).min(surface.y1 - surface.y0) / 2);
+                let (x, y) = (rng.gen_range(surface.x0 + r..=surface.x1 - r), rng.gen_range(surface.y0 + r..=surface.y1 - r));
+                scene.push(Rc::new(RefCell::new(Circle::new(id, x, y, r))));
+            },
+            _ => {
+                let (w, h) = (rng.gen_range(4..=20).min(surface.x1 - surface.x0), rng.gen_range(4..=20).min(surface.y1 - surface.y0));
+                let (x, y) = (rng.gen_range(surface.x0..=surface.x1 - w), rng.gen_range(surface.y0..=surface.y1 - h));
+                scene.push(Rc::new(RefCell::new(Rectangle::new(id, x, y, w, h))));
+            },
+        }
+    }
+    scene
+}
+
+// Boids evenly spaced on a circle, ids counting up from first_id
+pub fn spawn_ring(first_id: u32, (cx, cy): (i32, i32), radius: i32, count: usize, facing: f32) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+    let mut spawned: Vec<Rc<RefCell<dyn QuadObject>>> = vec![];
+    for index in 0..count {
+        let angle = 2.0 * PI * index as f32 / count as f32;
+        let (x, y) = (cx + (angle.cos() * radius as f32).round() as i32, cy + (angle.sin() * radius as f32).round() as i32);
+        spawned.push(Rc::new(RefCell::new(Boid::new(first_id + index as u32, x, y, facing))));
+    }
+    spawned
+}
+
+// Boids on a cols x rows grid starting at origin, row by row
+pub fn spawn_grid(first_id: u32, (ox, oy): (i32, i32), cols: usize, rows: usize, spacing: i32) -> Vec<Rc<RefCell<dyn QuadObject>>> {
+    let mut spawned: Vec<Rc<RefCell<dyn QuadObject>>> = vec![];
+    for row in 0..rows {
+        for col in 0..cols {
+            let id = first_id + (row * cols + col) as u32;
+            spawned.push(Rc::new(RefCell::new(Boid::new(id, ox + col as i32 * spacing, oy + row as i32 * spacing, 0.0))));
+        }
+    }
+    spawned
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quadtree::{point_distance, TreeSurface};
+    use super::{generate_scene, spawn_ring};
+
+    #[test]
+    fn generate_scene_is_the_same_for_a_seed() {
+        let surface = TreeSurface::from_size(0, 0, 300, 200);
+        let records = |seed: u64| generate_scene(60, seed, &surface).iter().map(|object| object.as_ref().borrow().to_bytes()).collect::<Vec<Vec<u8>>>();
+        assert_eq!(records(4), records(4));
+        assert_ne!(records(4), records(5));
+        assert!(generate_scene(60, 4, &surface).iter().all(|object| surface.contains(&object.as_ref().borrow().bounds())));
+    }
+
+    #[test]
+    fn spawn_ring_places_count_boids_on_the_radius() {
+        let ring = spawn_ring(10, (200, 150), 80, 12, 0.0);
+        assert_eq!(ring.len(), 12);
+        for (index, object) in ring.iter().enumerate() {
+            let object = object.as_ref().borrow();
+            assert_eq!(object.get_id(), 10 + index as u32);
+            // Positions are rounded to whole pixels
+            assert!((point_distance((200, 150), object.center()) - 80.0).abs() <= 1.0);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::quad_objects::{Boid, QuadObject};
+use crate::quadtree::TreeSurface;
+
+// --------------------
+// TestObject
+// --------------------
+// A plain area standing in for the real shapes in tests, with the weight and draw layer they do not let you pick
+// It can count the overlap checks made against it and logs the collisions it is told about
+pub struct TestObject {
+    pub id: u32,
+    pub surface: TreeSurface,
+    pub weight: u32,
+    pub z: i32,
+    pub checks: Option<Arc<AtomicUsize>>, // Bumped by every is_overlap, shows how much of the tree a traversal looked at
+    pub collisions: Vec<u32>, // Ids passed to on_collide, in call order
+}
+
+impl TestObject {
+    pub fn point(id: u32, x: i32, y: i32) -> TestObject {
+        TestObject::area(id, x, y, x, y)
+    }
+    pub fn area(id: u32, x0: i32, y0: i32, x1: i32, y1: i32) -> TestObject {
+        TestObject { id, surface: TreeSurface::from_size(x0, y0, x1, y1), weight: 1, z: 0, checks: None, collisions: vec![] }
+    }
+    pub fn with_weight(mut self, weight: u32) -> TestObject {
+        self.weight = weight;
+        self
+    }
+    pub fn with_z(mut self, z: i32) -> TestObject {
+        self.z = z;
+        self
+    }
+    pub fn counting(mut self, checks: &Arc<AtomicUsize>) -> TestObject {
+        self.checks = Some(Arc::clone(checks));
+        self
+    }
+    pub fn shared(self) -> Rc<RefCell<dyn QuadObject>> {
+        Rc::new(RefCell::new(self))
+    }
+}
+
+impl Display for TestObject {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TestObject {}", self.id)
+    }
+}
+
+impl QuadObject for TestObject {
+    fn get_id(&self) -> u32 { self.id }
+    fn set_id(&mut self, id: u32) { self.id = id; }
+    fn draw(&self) {}
+    fn highlight(&self) {}
+    fn z_index(&self) -> i32 { self.z }
+    fn weight(&self) -> u32 { self.weight }
+    fn svg_element(&self) -> String { String::new() }
+    fn to_bytes(&self) -> Vec<u8> { vec![] }
+    fn center(&self) -> (i32, i32) {
+        (((self.surface.x0 as i64 + self.surface.x1 as i64) / 2) as i32, ((self.surface.y0 as i64 + self.surface.y1 as i64) / 2) as i32)
+    }
+    fn bounds(&self) -> TreeSurface { self.surface }
+    fn is_overlap(&self, surface: &TreeSurface) -> bool {
+        if let Some(checks) = &self.checks { checks.fetch_add(1, Ordering::Relaxed); }
+        self.surface.intersects(surface)
+    }
+    fn update(&mut self) {}
+    fn translate(&mut self, dx: i32, dy: i32) {
+        self.surface = TreeSurface::from_size(self.surface.x0 + dx, self.surface.y0 + dy, self.surface.x1 + dx, self.surface.y1 + dy);
+    }
+    fn scale(&mut self, _factor: f32) {}
+    fn update_movement(&mut self, _rhs: &Rc<RefCell<dyn QuadObject>>) {}
+    fn on_collide(&mut self, other: &dyn QuadObject) { self.collisions.push(other.get_id()); }
+    fn get_boid(&self) -> Option<&Boid> { None }
+}